@@ -1,3 +1,5 @@
+use std::path::PathBuf;
+
 use clap::{Parser, Subcommand};
 use crate::config::config::Config;
 
@@ -6,6 +8,10 @@ use crate::config::config::Config;
 #[command(version = env!("CARGO_PKG_VERSION"))]
 #[command(about = "WAF Proxy Server", long_about = None)]
 pub struct Cli {
+    /// Path to config file
+    #[arg(short, long, global = true, env = "CENTAUR_CONFIG")]
+    pub config: Option<PathBuf>,
+
     #[command(subcommand)]
     pub command: Commands,
 }
@@ -25,12 +31,48 @@ pub enum Commands {
     Stats,
     /// Show loaded rules info
     Info,
+    /// Configuration commands
+    Config {
+        #[command(subcommand)]
+        command: ConfigCommands,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum ConfigCommands {
+    /// Validate config file and report all problems
+    Check,
 }
 
 impl Cli {
     pub fn parse() -> Self {
         <Self as Parser>::parse()
     }
+
+    // Команды, которым не нужен запущенный логгер и валидный конфиг
+    pub fn is_config_check(&self) -> bool {
+        matches!(self.command, Commands::Config { command: ConfigCommands::Check })
+    }
+
+    // Загрузка и полная проверка конфига
+    pub fn load_config(&self) -> Result<Config, String> {
+        let config = Config::load(self.config.as_deref())?;
+        config.validate().map_err(|errors| errors.to_string())?;
+        Ok(config)
+    }
+
+    pub fn check_config(&self) -> Result<(), Box<dyn std::error::Error>> {
+        match self.load_config() {
+            Ok(config) => {
+                println!("✓ Configuration is valid: {}", config.path.display());
+                Ok(())
+            }
+            Err(e) => {
+                eprintln!("✗ {}", e);
+                std::process::exit(1);
+            }
+        }
+    }
     
     pub fn execute(&self, config: Config) -> Result<(), Box<dyn std::error::Error>> {
         match &self.command {
@@ -59,10 +101,11 @@ impl Cli {
                 println!("Fetching statistics from admin API...");
                 Ok(())
             }
+            Commands::Config { command: ConfigCommands::Check } => self.check_config(),
             Commands::Info => {
                 println!("WAF Proxy Information:");
                 println!("  Version: {}", env!("CARGO_PKG_VERSION"));
-                println!("  Configuration loaded from: config.toml");
+                println!("  Configuration loaded from: {}", config.path.display());
                 Ok(())
             }
         }
@@ -1,5 +1,12 @@
 use serde::Deserialize;
 use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+// Переменная окружения с путём к конфигу
+pub const CONFIG_ENV: &str = "CENTAUR_CONFIG";
+// Префикс переопределений: CENTAUR_ADMIN_PORT, CENTAUR_UPSTREAMS__WEB__ADDRS и т.д.
+const ENV_PREFIX: &str = "CENTAUR_";
+const DEFAULT_CONFIG_PATH: &str = "config.toml";
 
 #[derive(PartialEq, Debug, Deserialize, Clone)]
 pub struct TracingConfig {
@@ -14,6 +21,46 @@ pub struct Config {
     pub servers: HashMap<String, ServerConfig>,
     pub upstreams: HashMap<String, UpstreamConfig>,
     pub tracing: Option<TracingConfig>,
+    pub audit_log: Option<AuditLogConfig>,
+    pub ban: Option<BanConfig>,
+    // Каталог с наборами правил, относительно файла конфигурации
+    #[serde(default = "default_rules_dir")]
+    pub rules_dir: String,
+    // Путь, из которого загружен конфиг
+    #[serde(skip)]
+    pub path: PathBuf,
+}
+
+// JSON audit log заблокированных и помеченных транзакций
+#[derive(PartialEq, Debug, Deserialize, Clone)]
+pub struct AuditLogConfig {
+    #[serde(default = "default_true")]
+    pub enabled: bool,
+    #[serde(default = "default_audit_path")]
+    pub path: String,
+    // Ротация по размеру: audit.json.1 ... audit.json.N
+    #[serde(default = "default_audit_max_size")]
+    pub max_size_mb: u64,
+    #[serde(default = "default_audit_max_files")]
+    pub max_files: usize,
+    #[serde(default = "default_audit_body_bytes")]
+    pub max_body_bytes: usize,
+    // Писать ли транзакции, пропущенные в режиме detect
+    #[serde(default = "default_true")]
+    pub log_detected: bool,
+}
+
+// Временный бан клиентов, набравших max_violations блокировок за window_secs
+#[derive(PartialEq, Debug, Deserialize, Clone)]
+pub struct BanConfig {
+    #[serde(default = "default_true")]
+    pub enabled: bool,
+    #[serde(default = "default_ban_max_violations")]
+    pub max_violations: u32,
+    #[serde(default = "default_ban_window")]
+    pub window_secs: u64,
+    #[serde(default = "default_ban_duration")]
+    pub ban_secs: u64,
 }
 
 #[derive(PartialEq, Debug, Deserialize, Clone)]
@@ -22,14 +69,461 @@ pub struct ServerConfig {
     pub max_body_size: Option<usize>,
     pub addr: String,  // Формат: "IP:port"
     pub upstreams: Vec<String>,  // Список имен upstream
+    // TLS-терминация на addr: сертификат по умолчанию
+    pub tls_cert: Option<String>,
+    pub tls_key: Option<String>,
+    // Дополнительные сертификаты, выбираемые по SNI
+    #[serde(default)]
+    pub certificates: Vec<SniCertificateConfig>,
+    // Ограничения частоты запросов для всего сервера
+    #[serde(default)]
+    pub rate_limits: Vec<RateLimitConfig>,
+    pub access: Option<AccessListConfig>,
+    // Балансировщики и прокси перед сервером, которым доверяем адрес клиента
+    #[serde(default)]
+    pub trusted_proxies: Vec<String>,
+    // Откуда брать адрес клиента, если соединение пришло от доверенного прокси
+    #[serde(default)]
+    pub real_ip_source: RealIpSource,
+    // Правила маршрутизации по пути и методу; проверяются по порядку до выбора по Host
+    #[serde(default)]
+    pub routes: Vec<RouteConfig>,
+    // Upstream для запросов, Host которых не совпал ни с одним server_names
+    pub default_upstream: Option<String>,
+    // Ответ на заблокированные запросы; upstream может задать свой
+    pub block_page: Option<BlockPageConfig>,
+}
+
+// Шаблоны ответа на заблокированный запрос. Плейсхолдеры: {{status}}, {{request_id}},
+// {{rule_id}}, {{timestamp}}. HTML или JSON выбирается по заголовку Accept
+#[derive(PartialEq, Debug, Deserialize, Clone)]
+pub struct BlockPageConfig {
+    // Код ответа при блокировке WAF вместо статуса из правила (deny,status:...)
+    pub status: Option<u16>,
+    // Без файлов используются встроенные шаблоны
+    pub html_file: Option<String>,
+    pub json_file: Option<String>,
+}
+
+// Правило маршрутизации: все заданные условия должны совпасть
+#[derive(PartialEq, Debug, Deserialize, Clone)]
+pub struct RouteConfig {
+    // Без учёта регистра и порта; если не задан - любой Host
+    pub host: Option<String>,
+    pub path_prefix: Option<String>,
+    pub path_regex: Option<String>,
+    // Пустой список - любой метод
+    #[serde(default)]
+    pub methods: Vec<String>,
+    pub upstream: String,
+}
+
+#[derive(PartialEq, Debug, Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum RealIpSource {
+    #[default]
+    XForwardedFor,
+    XRealIp,
+    Forwarded,
+    // Заголовок PROXY protocol v1/v2 в начале каждого соединения
+    ProxyProtocol,
+}
+
+// Статические списки доступа по IP/CIDR; allow имеет приоритет над deny
+#[derive(PartialEq, Debug, Deserialize, Clone)]
+pub struct AccessListConfig {
+    #[serde(default)]
+    pub allow: Vec<String>,
+    #[serde(default)]
+    pub deny: Vec<String>,
+    // Файлы со списками (один адрес или сеть на строку), перечитываются при изменении
+    pub allow_file: Option<String>,
+    pub deny_file: Option<String>,
+    // Запросы из allow не проверяются WAF
+    #[serde(default)]
+    pub allow_bypass_waf: bool,
+}
+
+#[derive(PartialEq, Debug, Deserialize, Clone)]
+pub struct SniCertificateConfig {
+    // Точные имена или "*.example.com"
+    pub server_names: Vec<String>,
+    pub cert: String,
+    pub key: String,
 }
 
 #[derive(PartialEq, Debug, Deserialize, Clone)]
 pub struct UpstreamConfig {
     pub addrs: Vec<String>,
     pub use_tls: bool,
+    // SNI для TLS-соединения с бэкендом
     pub sni: String,
+    // Имена хостов upstream'а: точные, "*.example.com" или "~регулярное выражение".
+    // Если не заданы, используется sni как точное имя
+    #[serde(default)]
+    pub server_names: Vec<String>,
     pub waf_rules: String,
+    // "block" - блокировать, "detect" - только логировать, "off" - без проверки
+    #[serde(default)]
+    pub mode: WafMode,
+    #[serde(default)]
+    pub lb_method: LbMethod,
+    // Веса бэкендов в порядке addrs (по умолчанию 1)
+    #[serde(default)]
+    pub weights: Vec<u32>,
+    // Заголовок для lb_method = "header_hash"
+    pub hash_header: Option<String>,
+    pub health_check: Option<HealthCheckConfig>,
+    pub passive_health: Option<PassiveHealthConfig>,
+    // Проверка ответов бэкенда (фазы 3 и 4 ModSecurity)
+    #[serde(default)]
+    pub inspect_response: bool,
+    pub max_response_body_size: Option<usize>,
+    #[serde(default)]
+    pub rate_limits: Vec<RateLimitConfig>,
+    pub access: Option<AccessListConfig>,
+    // Параметры TLS-соединения с бэкендами (при use_tls = true)
+    pub tls: Option<UpstreamTlsConfig>,
+    #[serde(default)]
+    pub timeouts: UpstreamTimeoutsConfig,
+    pub retry: Option<RetryConfig>,
+    pub circuit_breaker: Option<CircuitBreakerConfig>,
+    pub block_page: Option<BlockPageConfig>,
+    #[serde(default)]
+    pub request_body: RequestBodyConfig,
+}
+
+// Таймауты соединений с бэкендами, в миллисекундах
+#[derive(PartialEq, Debug, Deserialize, Clone)]
+pub struct UpstreamTimeoutsConfig {
+    // Установка TCP-соединения
+    #[serde(default = "default_connect_timeout")]
+    pub connect_ms: u64,
+    // TCP вместе с TLS handshake
+    pub total_connect_ms: Option<u64>,
+    // Ожидание каждого чтения и записи
+    #[serde(default = "default_io_timeout")]
+    pub read_ms: u64,
+    #[serde(default = "default_io_timeout")]
+    pub write_ms: u64,
+    // Сколько простаивающее соединение живёт в пуле
+    pub idle_ms: Option<u64>,
+}
+
+impl Default for UpstreamTimeoutsConfig {
+    fn default() -> Self {
+        Self {
+            connect_ms: default_connect_timeout(),
+            total_connect_ms: None,
+            read_ms: default_io_timeout(),
+            write_ms: default_io_timeout(),
+            idle_ms: None,
+        }
+    }
+}
+
+// Проверка тела запроса: фрагменты по мере получения передаются в ModSecurity
+#[derive(PartialEq, Debug, Deserialize, Clone, Default)]
+pub struct RequestBodyConfig {
+    #[serde(default)]
+    pub mode: RequestBodyMode,
+    // Сколько байт тела проверяется, по умолчанию max_body_size сервера
+    pub max_size: Option<usize>,
+    // Удержанное тело больше этого размера пишется во временный файл
+    pub spill_threshold: Option<usize>,
+    // Каталог временных файлов, по умолчанию системный
+    pub spill_dir: Option<String>,
+}
+
+#[derive(PartialEq, Debug, Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum RequestBodyMode {
+    // Тело удерживается до решения WAF и уходит на бэкенд только проверенным
+    #[default]
+    Buffer,
+    // Фрагменты сразу уходят на бэкенд; при блокировке запрос к нему обрывается
+    Stream,
+}
+
+// Circuit breaker каждого бэкенда: открывается при доле ошибок не меньше error_rate
+// за window_secs, через open_secs пропускает half_open_requests пробных запросов
+#[derive(PartialEq, Debug, Deserialize, Clone)]
+pub struct CircuitBreakerConfig {
+    #[serde(default = "default_breaker_window")]
+    pub window_secs: u64,
+    // Меньше запросов в окне - решение не принимается
+    #[serde(default = "default_breaker_min_requests")]
+    pub min_requests: u32,
+    #[serde(default = "default_breaker_error_rate")]
+    pub error_rate: f64,
+    // Ответы медленнее этого считаются ошибкой
+    pub slow_ms: Option<u64>,
+    #[serde(default = "default_breaker_open")]
+    pub open_secs: u64,
+    #[serde(default = "default_breaker_half_open")]
+    pub half_open_requests: u32,
+    // Ответ, когда открыты все бэкенды upstream'а
+    #[serde(default = "default_breaker_status")]
+    pub status: u16,
+    // HTML-страница для этого ответа
+    pub page_file: Option<String>,
+}
+
+// Повтор идемпотентных запросов на другом бэкенде
+#[derive(PartialEq, Debug, Deserialize, Clone)]
+pub struct RetryConfig {
+    // Сколько раз повторять после первой попытки
+    #[serde(default = "default_max_retries")]
+    pub max_retries: u32,
+    #[serde(default = "default_true")]
+    pub on_connect_failure: bool,
+    // Ответы бэкенда, после которых запрос повторяется, например [502, 503, 504]
+    #[serde(default)]
+    pub on_status: Vec<u16>,
+}
+
+#[derive(PartialEq, Debug, Deserialize, Clone)]
+pub struct UpstreamTlsConfig {
+    // SNI вместо sni upstream'а; пустая строка - не отправлять SNI
+    pub sni: Option<String>,
+    // PEM с доверенными CA вместо системного хранилища
+    pub ca_file: Option<String>,
+    // Клиентский сертификат и ключ для mTLS
+    pub client_cert: Option<String>,
+    pub client_key: Option<String>,
+    // Проверять сертификат бэкенда и имя хоста в нём
+    #[serde(default = "default_true")]
+    pub verify_cert: bool,
+    #[serde(default = "default_true")]
+    pub verify_hostname: bool,
+    // Минимальная версия протокола: "1.0", "1.1", "1.2" или "1.3"
+    pub min_version: Option<TlsVersion>,
+}
+
+#[derive(PartialEq, PartialOrd, Debug, Deserialize, Clone, Copy)]
+pub enum TlsVersion {
+    #[serde(rename = "1.0")]
+    Tls10,
+    #[serde(rename = "1.1")]
+    Tls11,
+    #[serde(rename = "1.2")]
+    Tls12,
+    #[serde(rename = "1.3")]
+    Tls13,
+}
+
+impl TlsVersion {
+    // Версия по имени из openssl: "TLSv1.2"
+    pub fn from_openssl(name: &str) -> Option<Self> {
+        match name {
+            "TLSv1" => Some(TlsVersion::Tls10),
+            "TLSv1.1" => Some(TlsVersion::Tls11),
+            "TLSv1.2" => Some(TlsVersion::Tls12),
+            "TLSv1.3" => Some(TlsVersion::Tls13),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TlsVersion::Tls10 => "1.0",
+            TlsVersion::Tls11 => "1.1",
+            TlsVersion::Tls12 => "1.2",
+            TlsVersion::Tls13 => "1.3",
+        }
+    }
+}
+
+#[derive(PartialEq, Debug, Deserialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum HealthCheckKind {
+    Http,
+    Tcp,
+}
+
+// Активные проверки бэкендов
+#[derive(PartialEq, Debug, Deserialize, Clone)]
+pub struct HealthCheckConfig {
+    #[serde(rename = "type")]
+    pub kind: HealthCheckKind,
+    #[serde(default = "default_health_path")]
+    pub path: String,
+    #[serde(default = "default_health_interval")]
+    pub interval_secs: u64,
+    #[serde(default = "default_health_timeout")]
+    pub timeout_ms: u64,
+    // Если не задан, здоровым считается любой 2xx/3xx
+    pub expected_status: Option<u16>,
+    #[serde(default = "default_threshold")]
+    pub healthy_threshold: u32,
+    #[serde(default = "default_threshold")]
+    pub unhealthy_threshold: u32,
+}
+
+// Пассивное исключение бэкенда после подряд идущих ошибок соединения/5xx
+#[derive(PartialEq, Debug, Deserialize, Clone)]
+pub struct PassiveHealthConfig {
+    #[serde(default = "default_max_fails")]
+    pub max_fails: u32,
+    #[serde(default = "default_fail_timeout")]
+    pub fail_timeout_secs: u64,
+}
+
+// Политика ограничения частоты запросов
+#[derive(PartialEq, Debug, Deserialize, Clone)]
+pub struct RateLimitConfig {
+    #[serde(default)]
+    pub key: RateLimitKey,
+    // Имя заголовка для key = "header"
+    pub header: Option<String>,
+    // Политика применяется только к путям с этим префиксом
+    pub path_prefix: Option<String>,
+    #[serde(default)]
+    pub algorithm: RateLimitAlgorithm,
+    // Не больше requests запросов за period_secs
+    pub requests: u32,
+    #[serde(default = "default_rate_limit_period")]
+    pub period_secs: u64,
+    // Ёмкость token bucket, по умолчанию равна requests
+    pub burst: Option<u32>,
+}
+
+#[derive(PartialEq, Debug, Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum RateLimitKey {
+    #[default]
+    Ip,
+    Header,
+    Path,
+    Upstream,
+}
+
+#[derive(PartialEq, Debug, Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum RateLimitAlgorithm {
+    #[default]
+    TokenBucket,
+    SlidingWindow,
+}
+
+fn default_rate_limit_period() -> u64 {
+    1
+}
+
+fn default_rules_dir() -> String {
+    "rules".to_string()
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_audit_path() -> String {
+    "logs/audit.json".to_string()
+}
+
+fn default_audit_max_size() -> u64 {
+    100
+}
+
+fn default_audit_max_files() -> usize {
+    5
+}
+
+fn default_audit_body_bytes() -> usize {
+    4096
+}
+
+fn default_ban_max_violations() -> u32 {
+    10
+}
+
+fn default_ban_window() -> u64 {
+    60
+}
+
+fn default_ban_duration() -> u64 {
+    600
+}
+
+fn default_health_path() -> String {
+    "/".to_string()
+}
+
+fn default_health_interval() -> u64 {
+    5
+}
+
+fn default_health_timeout() -> u64 {
+    1000
+}
+
+fn default_connect_timeout() -> u64 {
+    5000
+}
+
+fn default_io_timeout() -> u64 {
+    60_000
+}
+
+fn default_max_retries() -> u32 {
+    1
+}
+
+fn default_breaker_window() -> u64 {
+    10
+}
+
+fn default_breaker_min_requests() -> u32 {
+    20
+}
+
+fn default_breaker_error_rate() -> f64 {
+    0.5
+}
+
+fn default_breaker_open() -> u64 {
+    30
+}
+
+fn default_breaker_half_open() -> u32 {
+    3
+}
+
+fn default_breaker_status() -> u16 {
+    503
+}
+
+fn default_threshold() -> u32 {
+    2
+}
+
+fn default_max_fails() -> u32 {
+    3
+}
+
+fn default_fail_timeout() -> u64 {
+    30
+}
+
+#[derive(PartialEq, Debug, Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum WafMode {
+    #[default]
+    Block,
+    Detect,
+    Off,
+}
+
+#[derive(PartialEq, Debug, Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum LbMethod {
+    #[default]
+    RoundRobin,
+    Weighted,
+    LeastConn,
+    IpHash,
+    HeaderHash,
 }
 
 // impl UpstreamConfig {
@@ -39,12 +533,54 @@ pub struct UpstreamConfig {
 //     }
 // }
 
+impl UpstreamConfig {
+    // Имена хостов для маршрутизации; старые конфиги без server_names маршрутизируются по sni
+    pub fn host_names(&self) -> &[String] {
+        if self.server_names.is_empty() {
+            std::slice::from_ref(&self.sni)
+        } else {
+            &self.server_names
+        }
+    }
+}
+
 impl Config {
-    pub fn load() -> Config {
-        let config_path = format!("{}/config.toml", env!("CARGO_MANIFEST_DIR"));
+    // Путь к конфигу: --config, затем CENTAUR_CONFIG, затем ./config.toml
+    pub fn load(path: Option<&Path>) -> Result<Config, String> {
+        let config_path = path
+            .map(Path::to_path_buf)
+            .or_else(|| std::env::var_os(CONFIG_ENV).map(PathBuf::from))
+            .unwrap_or_else(|| PathBuf::from(DEFAULT_CONFIG_PATH));
+
         let config_str = std::fs::read_to_string(&config_path)
-            .expect("Failed to read config.toml");
-        toml::from_str(&config_str).expect("Failed to parse config.toml")
+            .map_err(|e| format!("Failed to read {}: {}", config_path.display(), e))?;
+        let mut table: toml::Table = toml::from_str(&config_str)
+            .map_err(|e| format!("Failed to parse {}: {}", config_path.display(), e))?;
+
+        apply_env_overrides(&mut table, std::env::vars());
+
+        let mut config: Config = toml::Value::Table(table)
+            .try_into()
+            .map_err(|e| format!("Invalid config {}: {}", config_path.display(), e))?;
+        config.path = config_path;
+        Ok(config)
+    }
+
+    // Относительные пути считаются от каталога файла конфигурации
+    pub fn resolve_path(&self, path: impl AsRef<Path>) -> PathBuf {
+        let path = path.as_ref();
+        if path.is_absolute() {
+            return path.to_path_buf();
+        }
+        self.path
+            .parent()
+            .map(|dir| dir.join(path))
+            .unwrap_or_else(|| path.to_path_buf())
+    }
+
+    // Каталог набора правил upstream'а
+    pub fn rules_path(&self, rule_set: &str) -> PathBuf {
+        self.resolve_path(&self.rules_dir).join(rule_set)
     }
 
     pub fn get_admin_port(&self) -> u16 {
@@ -93,4 +629,44 @@ impl Config {
         }
         result
     }
-}
\ No newline at end of file
+}
+
+// CENTAUR_SERVERS__SERVER1__ADDR="0.0.0.0:8080" -> servers.Server1.addr
+// Значение разбирается как TOML (числа, массивы, bool), иначе берётся строкой.
+fn apply_env_overrides(table: &mut toml::Table, vars: impl Iterator<Item = (String, String)>) {
+    for (name, raw) in vars {
+        let Some(key) = name.strip_prefix(ENV_PREFIX) else {
+            continue;
+        };
+        if name == CONFIG_ENV || key.is_empty() {
+            continue;
+        }
+
+        let segments: Vec<&str> = key.split("__").collect();
+        let value = format!("v = {}", raw)
+            .parse::<toml::Table>()
+            .ok()
+            .and_then(|mut t| t.remove("v"))
+            .unwrap_or(toml::Value::String(raw));
+
+        let mut current = &mut *table;
+        for (i, segment) in segments.iter().enumerate() {
+            // Имена серверов и upstream'ов регистрозависимы, ищем существующий ключ
+            let existing = current.keys().find(|k| k.eq_ignore_ascii_case(segment)).cloned();
+            let key = existing.unwrap_or_else(|| segment.to_lowercase());
+
+            if i + 1 == segments.len() {
+                current.insert(key, value);
+                break;
+            }
+
+            let entry = current
+                .entry(key)
+                .or_insert_with(|| toml::Value::Table(toml::Table::new()));
+            current = match entry {
+                toml::Value::Table(t) => t,
+                _ => break,
+            };
+        }
+    }
+}
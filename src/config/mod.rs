@@ -1 +1,2 @@
-pub mod config;
\ No newline at end of file
+pub mod config;
+pub mod validate;
\ No newline at end of file
@@ -0,0 +1,365 @@
+use std::fmt;
+use std::net::{SocketAddr, ToSocketAddrs};
+
+use regex::Regex;
+
+use crate::config::config::{AccessListConfig, BlockPageConfig, Config, HealthCheckKind, LbMethod, RateLimitConfig, RateLimitKey, RealIpSource, RequestBodyMode};
+use crate::proxy::access::{load_file, Cidr};
+use crate::proxy::routes::HostPattern;
+
+// Pingora делает не больше 16 попыток на запрос, включая первую
+const MAX_RETRIES: u32 = 15;
+
+// Одна проблема в конфиге с указанием ключа
+#[derive(Debug, Clone)]
+pub struct ConfigIssue {
+    pub key: String,
+    pub message: String,
+}
+
+// Все проблемы, найденные при проверке
+#[derive(Debug)]
+pub struct ConfigErrors {
+    pub file: String,
+    pub issues: Vec<ConfigIssue>,
+}
+
+impl fmt::Display for ConfigErrors {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} has {} error(s):", self.file, self.issues.len())?;
+        for issue in &self.issues {
+            write!(f, "\n  {}: {}: {}", self.file, issue.key, issue.message)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ConfigErrors {}
+
+impl Config {
+    /// Проверяет конфиг целиком и возвращает все найденные проблемы разом
+    pub fn validate(&self) -> Result<(), ConfigErrors> {
+        let mut issues = Vec::new();
+        let mut issue = |key: String, message: String| issues.push(ConfigIssue { key, message });
+
+        if self.servers.is_empty() {
+            issue("servers".to_string(), "no servers defined".to_string());
+        }
+
+        // Сортируем, чтобы порядок ошибок не зависел от HashMap
+        let mut server_names: Vec<&String> = self.servers.keys().collect();
+        server_names.sort();
+
+        let admin_addr = SocketAddr::from(([127, 0, 0, 1], self.admin_port));
+        let mut listeners: Vec<(SocketAddr, String)> = vec![(admin_addr, "admin_port".to_string())];
+
+        for name in server_names {
+            let server = &self.servers[name];
+            let key = format!("servers.{}", name);
+
+            match server.addr.parse::<SocketAddr>() {
+                Ok(addr) => {
+                    if let Some((_, other)) = listeners.iter().find(|(l, _)| listen_conflict(*l, addr)) {
+                        issue(format!("{}.addr", key), format!("'{}' is already used by {}", server.addr, other));
+                    }
+                    listeners.push((addr, format!("{}.addr", key)));
+                }
+                Err(e) => issue(format!("{}.addr", key), format!("invalid socket address '{}': {}", server.addr, e)),
+            }
+
+            if let Some(listen_addr) = &server.listen_addr {
+                if let Err(e) = listen_addr.parse::<SocketAddr>() {
+                    issue(format!("{}.listen_addr", key), format!("invalid socket address '{}': {}", listen_addr, e));
+                }
+            }
+
+            if server.upstreams.is_empty() {
+                issue(format!("{}.upstreams", key), "no upstreams listed".to_string());
+            }
+            for (i, upstream) in server.upstreams.iter().enumerate() {
+                if !self.upstreams.contains_key(upstream) {
+                    issue(format!("{}.upstreams[{}]", key, i), format!("unknown upstream '{}'", upstream));
+                }
+            }
+
+            if let Some(default_upstream) = &server.default_upstream {
+                if !server.upstreams.contains(default_upstream) {
+                    issue(
+                        format!("{}.default_upstream", key),
+                        format!("'{}' is not listed in upstreams of this server", default_upstream),
+                    );
+                }
+            }
+            for (i, route) in server.routes.iter().enumerate() {
+                if !server.upstreams.contains(&route.upstream) {
+                    issue(
+                        format!("{}.routes[{}].upstream", key, i),
+                        format!("'{}' is not listed in upstreams of this server", route.upstream),
+                    );
+                }
+                if let Some(Err(e)) = route.host.as_deref().map(HostPattern::parse) {
+                    issue(format!("{}.routes[{}].host", key, i), e);
+                }
+                if let Some(path_regex) = &route.path_regex {
+                    if let Err(e) = Regex::new(path_regex) {
+                        issue(format!("{}.routes[{}].path_regex", key, i), format!("invalid regex: {}", e));
+                    }
+                }
+            }
+
+            match (&server.tls_cert, &server.tls_key) {
+                (Some(_), None) => issue(format!("{}.tls_key", key), "tls_cert is set without tls_key".to_string()),
+                (None, Some(_)) => issue(format!("{}.tls_cert", key), "tls_key is set without tls_cert".to_string()),
+                _ => {}
+            }
+            let tls_files = server.tls_cert.iter().map(|p| ("tls_cert".to_string(), p))
+                .chain(server.tls_key.iter().map(|p| ("tls_key".to_string(), p)));
+            let sni_files = server.certificates.iter().enumerate().flat_map(|(i, c)| {
+                [
+                    (format!("certificates[{}].cert", i), &c.cert),
+                    (format!("certificates[{}].key", i), &c.key),
+                ]
+            });
+            for (field, path) in tls_files.chain(sni_files) {
+                if !self.resolve_path(path).is_file() {
+                    issue(format!("{}.{}", key, field), format!("file '{}' not found", path));
+                }
+            }
+            for (i, cert) in server.certificates.iter().enumerate() {
+                if cert.server_names.is_empty() {
+                    issue(format!("{}.certificates[{}].server_names", key, i), "no server names listed".to_string());
+                }
+            }
+            validate_rate_limits(&key, &server.rate_limits, &mut issue);
+            for (i, proxy) in server.trusted_proxies.iter().enumerate() {
+                if let Err(e) = proxy.parse::<Cidr>() {
+                    issue(format!("{}.trusted_proxies[{}]", key, i), e);
+                }
+            }
+            if server.real_ip_source == RealIpSource::ProxyProtocol && server.trusted_proxies.is_empty() {
+                issue(format!("{}.trusted_proxies", key), "required for real_ip_source = \"proxy_protocol\"".to_string());
+            }
+            if let Some(access) = &server.access {
+                validate_access(self, &key, access, &mut issue);
+            }
+            if let Some(page) = &server.block_page {
+                validate_block_page(self, &key, page, &mut issue);
+            }
+        }
+
+        if let Some(ban) = &self.ban {
+            if ban.max_violations == 0 {
+                issue("ban.max_violations".to_string(), "must be greater than 0".to_string());
+            }
+            if ban.window_secs == 0 {
+                issue("ban.window_secs".to_string(), "must be greater than 0".to_string());
+            }
+            if ban.ban_secs == 0 {
+                issue("ban.ban_secs".to_string(), "must be greater than 0".to_string());
+            }
+        }
+
+        let mut upstream_names: Vec<&String> = self.upstreams.keys().collect();
+        upstream_names.sort();
+
+        for name in upstream_names {
+            let upstream = &self.upstreams[name];
+            let key = format!("upstreams.{}", name);
+
+            if upstream.addrs.is_empty() {
+                issue(format!("{}.addrs", key), "no backend addresses".to_string());
+            }
+            for (i, addr) in upstream.addrs.iter().enumerate() {
+                // Бэкенд может быть задан именем хоста, поэтому адрес резолвится
+                if let Err(e) = addr.to_socket_addrs() {
+                    issue(format!("{}.addrs[{}]", key, i), format!("invalid socket address '{}': {}", addr, e));
+                }
+            }
+
+            for (i, name) in upstream.server_names.iter().enumerate() {
+                if let Err(e) = HostPattern::parse(name) {
+                    issue(format!("{}.server_names[{}]", key, i), e);
+                }
+            }
+
+            if upstream.weights.len() > upstream.addrs.len() {
+                issue(
+                    format!("{}.weights", key),
+                    format!("{} weights for {} addrs", upstream.weights.len(), upstream.addrs.len()),
+                );
+            }
+            if upstream.weights.contains(&0) {
+                issue(format!("{}.weights", key), "weights must be greater than 0".to_string());
+            }
+            if upstream.lb_method == LbMethod::HeaderHash && upstream.hash_header.is_none() {
+                issue(format!("{}.hash_header", key), "required for lb_method = \"header_hash\"".to_string());
+            }
+            if let Some(health_check) = &upstream.health_check {
+                if health_check.interval_secs == 0 {
+                    issue(format!("{}.health_check.interval_secs", key), "must be greater than 0".to_string());
+                }
+                // Проба по HTTP идёт открытым текстом; TLS-бэкенд проверяется только соединением
+                if health_check.kind == HealthCheckKind::Http && upstream.use_tls {
+                    issue(format!("{}.health_check.type", key), "\"http\" is not supported with use_tls = true, use \"tcp\"".to_string());
+                }
+            }
+            validate_rate_limits(&key, &upstream.rate_limits, &mut issue);
+            if let Some(access) = &upstream.access {
+                validate_access(self, &key, access, &mut issue);
+            }
+            if let Some(page) = &upstream.block_page {
+                validate_block_page(self, &key, page, &mut issue);
+            }
+            let timeouts = [
+                ("connect_ms", Some(upstream.timeouts.connect_ms)),
+                ("total_connect_ms", upstream.timeouts.total_connect_ms),
+                ("read_ms", Some(upstream.timeouts.read_ms)),
+                ("write_ms", Some(upstream.timeouts.write_ms)),
+                ("idle_ms", upstream.timeouts.idle_ms),
+            ];
+            for (field, value) in timeouts {
+                if value == Some(0) {
+                    issue(format!("{}.timeouts.{}", key, field), "must be greater than 0".to_string());
+                }
+            }
+            if let Some(retry) = &upstream.retry {
+                if retry.max_retries == 0 || retry.max_retries > MAX_RETRIES {
+                    issue(format!("{}.retry.max_retries", key), format!("must be between 1 and {}", MAX_RETRIES));
+                }
+                for (i, status) in retry.on_status.iter().enumerate() {
+                    if !(400..=599).contains(status) {
+                        issue(format!("{}.retry.on_status[{}]", key, i), format!("{} is not an error status", status));
+                    }
+                }
+            }
+            if let Some(breaker) = &upstream.circuit_breaker {
+                let counts = [
+                    ("window_secs", breaker.window_secs),
+                    ("min_requests", breaker.min_requests as u64),
+                    ("open_secs", breaker.open_secs),
+                    ("half_open_requests", breaker.half_open_requests as u64),
+                ];
+                for (field, value) in counts {
+                    if value == 0 {
+                        issue(format!("{}.circuit_breaker.{}", key, field), "must be greater than 0".to_string());
+                    }
+                }
+                if !(breaker.error_rate > 0.0 && breaker.error_rate <= 1.0) {
+                    issue(format!("{}.circuit_breaker.error_rate", key), "must be in (0, 1]".to_string());
+                }
+                if !(400..=599).contains(&breaker.status) {
+                    issue(format!("{}.circuit_breaker.status", key), format!("{} is not an error status", breaker.status));
+                }
+                if let Some(page) = &breaker.page_file {
+                    if !self.resolve_path(page).is_file() {
+                        issue(format!("{}.circuit_breaker.page_file", key), format!("file '{}' not found", page));
+                    }
+                }
+            }
+            let body = &upstream.request_body;
+            if body.max_size == Some(0) {
+                issue(format!("{}.request_body.max_size", key), "must be greater than 0".to_string());
+            }
+            if body.spill_dir.is_some() && body.spill_threshold.is_none() {
+                issue(format!("{}.request_body.spill_dir", key), "has no effect without spill_threshold".to_string());
+            }
+            if body.spill_threshold.is_some() && body.mode == RequestBodyMode::Stream {
+                issue(format!("{}.request_body.spill_threshold", key), "has no effect with mode = \"stream\"".to_string());
+            }
+            if let Some(dir) = &body.spill_dir {
+                if !self.resolve_path(dir).is_dir() {
+                    issue(format!("{}.request_body.spill_dir", key), format!("directory '{}' not found", dir));
+                }
+            }
+            if let Some(tls) = &upstream.tls {
+                if !upstream.use_tls {
+                    issue(format!("{}.tls", key), "has no effect without use_tls = true".to_string());
+                }
+                match (&tls.client_cert, &tls.client_key) {
+                    (Some(_), None) => issue(format!("{}.tls.client_key", key), "client_cert is set without client_key".to_string()),
+                    (None, Some(_)) => issue(format!("{}.tls.client_cert", key), "client_key is set without client_cert".to_string()),
+                    _ => {}
+                }
+                let files = [("ca_file", &tls.ca_file), ("client_cert", &tls.client_cert), ("client_key", &tls.client_key)];
+                for (field, path) in files {
+                    if let Some(path) = path {
+                        if !self.resolve_path(path).is_file() {
+                            issue(format!("{}.tls.{}", key, field), format!("file '{}' not found", path));
+                        }
+                    }
+                }
+            }
+
+            let rules_dir = self.rules_path(&upstream.waf_rules);
+            if !rules_dir.is_dir() {
+                issue(
+                    format!("{}.waf_rules", key),
+                    format!("rules directory '{}' not found", rules_dir.display()),
+                );
+            }
+        }
+
+        if issues.is_empty() {
+            Ok(())
+        } else {
+            Err(ConfigErrors {
+                file: self.path.display().to_string(),
+                issues,
+            })
+        }
+    }
+}
+
+// Файлы списков разрешаются относительно конфига, как и rules_dir
+fn validate_access(config: &Config, key: &str, access: &AccessListConfig, issue: &mut impl FnMut(String, String)) {
+    for (field, entries) in [("allow", &access.allow), ("deny", &access.deny)] {
+        for (i, entry) in entries.iter().enumerate() {
+            if let Err(e) = entry.parse::<Cidr>() {
+                issue(format!("{}.access.{}[{}]", key, field, i), e);
+            }
+        }
+    }
+    for (field, file) in [("allow_file", &access.allow_file), ("deny_file", &access.deny_file)] {
+        if let Some(file) = file {
+            if let Err(e) = load_file(&config.resolve_path(file)) {
+                issue(format!("{}.access.{}", key, field), e);
+            }
+        }
+    }
+}
+
+fn validate_block_page(config: &Config, key: &str, page: &BlockPageConfig, issue: &mut impl FnMut(String, String)) {
+    if let Some(status) = page.status.filter(|status| !(400..=599).contains(status)) {
+        issue(format!("{}.block_page.status", key), format!("{} is not an error status", status));
+    }
+    for (field, file) in [("html_file", &page.html_file), ("json_file", &page.json_file)] {
+        if let Some(file) = file {
+            if !config.resolve_path(file).is_file() {
+                issue(format!("{}.block_page.{}", key, field), format!("file '{}' not found", file));
+            }
+        }
+    }
+}
+
+fn validate_rate_limits(key: &str, rate_limits: &[RateLimitConfig], issue: &mut impl FnMut(String, String)) {
+    for (i, limit) in rate_limits.iter().enumerate() {
+        let key = format!("{}.rate_limits[{}]", key, i);
+        if limit.requests == 0 {
+            issue(format!("{}.requests", key), "must be greater than 0".to_string());
+        }
+        if limit.period_secs == 0 {
+            issue(format!("{}.period_secs", key), "must be greater than 0".to_string());
+        }
+        if limit.key == RateLimitKey::Header && limit.header.is_none() {
+            issue(format!("{}.header", key), "required for key = \"header\"".to_string());
+        }
+        if limit.key == RateLimitKey::Path && limit.path_prefix.is_none() {
+            issue(format!("{}.path_prefix", key), "required for key = \"path\"".to_string());
+        }
+    }
+}
+
+// 0.0.0.0:port конфликтует с любым адресом на том же порту
+fn listen_conflict(a: SocketAddr, b: SocketAddr) -> bool {
+    a.port() == b.port() && (a.ip() == b.ip() || a.ip().is_unspecified() || b.ip().is_unspecified())
+}
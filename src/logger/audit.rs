@@ -0,0 +1,203 @@
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::OnceLock;
+use std::thread;
+
+use serde::Serialize;
+use tracing::{error, info, warn};
+
+use crate::config::config::{AuditLogConfig, Config};
+use crate::waf::MatchedRule;
+
+static AUDIT_LOG: OnceLock<AuditLogger> = OnceLock::new();
+
+// Сколько записей ждёт потока записи; при переполнении новые записи отбрасываются
+const QUEUE_SIZE: usize = 10_000;
+
+/// Запись аудита, по духу совместимая с JSON audit log ModSecurity
+#[derive(Serialize)]
+pub struct AuditRecord {
+    pub transaction: AuditTransaction,
+}
+
+#[derive(Serialize)]
+pub struct AuditTransaction {
+    pub time_stamp: String,
+    pub request_id: String,
+    pub client_ip: String,
+    pub server: String,
+    pub upstream: Option<String>,
+    pub request: AuditRequest,
+    pub response: AuditResponse,
+    // "blocked" или "detected"
+    pub action: String,
+    pub messages: Vec<AuditMessage>,
+}
+
+#[derive(Serialize)]
+pub struct AuditRequest {
+    pub method: String,
+    pub uri: String,
+    pub http_version: String,
+    pub headers: BTreeMap<String, String>,
+    pub body: String,
+    pub body_truncated: bool,
+}
+
+#[derive(Serialize)]
+pub struct AuditResponse {
+    pub http_code: u16,
+}
+
+#[derive(Serialize)]
+pub struct AuditMessage {
+    pub source: String,
+    pub blocked: bool,
+    pub reason: String,
+    pub rule_id: u32,
+    pub rules: Vec<MatchedRule>,
+}
+
+// Записи сериализуются в обработчике запроса, а пишутся и ротируются
+// в отдельном потоке, чтобы файловый ввод-вывод не занимал воркеры runtime
+pub struct AuditLogger {
+    config: AuditLogConfig,
+    sender: SyncSender<Vec<u8>>,
+}
+
+struct AuditWriter {
+    // path из конфига относительно каталога файла конфигурации
+    path: PathBuf,
+    max_size: u64,
+    max_files: usize,
+    file: File,
+    size: u64,
+}
+
+/// Инициализирует audit log, если он включён в конфиге
+pub fn init_audit_log(config: &Config) {
+    let Some(cfg) = config.audit_log.as_ref().filter(|cfg| cfg.enabled) else {
+        return;
+    };
+
+    let path = config.resolve_path(&cfg.path);
+    match AuditLogger::new(cfg.clone(), path.clone()) {
+        Ok(logger) => {
+            let _ = AUDIT_LOG.set(logger);
+            info!(path = %path.display(), "Audit log initialized");
+        }
+        Err(e) => error!(path = %path.display(), error = %e, "Failed to open audit log"),
+    }
+}
+
+pub fn audit_log() -> Option<&'static AuditLogger> {
+    AUDIT_LOG.get()
+}
+
+impl AuditLogger {
+    pub fn new(config: AuditLogConfig, path: PathBuf) -> io::Result<Self> {
+        let file = open_append(&path)?;
+        let size = file.metadata()?.len();
+        let writer = AuditWriter {
+            path,
+            max_size: config.max_size_mb * 1024 * 1024,
+            max_files: config.max_files,
+            file,
+            size,
+        };
+
+        let (sender, receiver) = mpsc::sync_channel(QUEUE_SIZE);
+        thread::Builder::new()
+            .name("audit-log".to_string())
+            .spawn(move || writer.run(receiver))?;
+
+        Ok(Self { config, sender })
+    }
+
+    pub fn config(&self) -> &AuditLogConfig {
+        &self.config
+    }
+
+    /// Обрезает тело запроса до max_body_bytes
+    pub fn truncate_body(&self, body: &[u8]) -> (String, bool) {
+        let truncated = body.len() > self.config.max_body_bytes;
+        let end = body.len().min(self.config.max_body_bytes);
+        (String::from_utf8_lossy(&body[..end]).to_string(), truncated)
+    }
+
+    // Ставит запись в очередь потока записи, не блокируя вызывающего
+    pub fn write(&self, record: &AuditRecord) {
+        let mut line = match serde_json::to_vec(record) {
+            Ok(line) => line,
+            Err(e) => {
+                error!(error = %e, "Failed to serialize audit record");
+                return;
+            }
+        };
+        line.push(b'\n');
+
+        match self.sender.try_send(line) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => warn!("Audit log queue is full, record dropped"),
+            Err(TrySendError::Disconnected(_)) => error!("Audit log writer has stopped, record dropped"),
+        }
+    }
+}
+
+impl AuditWriter {
+    fn run(mut self, receiver: Receiver<Vec<u8>>) {
+        for line in receiver {
+            self.write(&line);
+        }
+    }
+
+    fn write(&mut self, line: &[u8]) {
+        if self.max_size > 0 && self.size + line.len() as u64 > self.max_size {
+            if let Err(e) = self.rotate() {
+                error!(path = %self.path.display(), error = %e, "Failed to rotate audit log");
+            }
+        }
+
+        match self.file.write_all(line) {
+            Ok(()) => self.size += line.len() as u64,
+            Err(e) => error!(path = %self.path.display(), error = %e, "Failed to write audit record"),
+        }
+    }
+
+    // audit.json -> audit.json.1 -> ... -> audit.json.N (удаляется)
+    fn rotate(&mut self) -> io::Result<()> {
+        let path = &self.path;
+        let rotated = |n: usize| {
+            let mut name = path.as_os_str().to_owned();
+            name.push(format!(".{}", n));
+            PathBuf::from(name)
+        };
+
+        if self.max_files == 0 {
+            fs::remove_file(path)?;
+        } else {
+            let _ = fs::remove_file(rotated(self.max_files));
+            for n in (1..self.max_files).rev() {
+                let from = rotated(n);
+                if from.exists() {
+                    fs::rename(&from, rotated(n + 1))?;
+                }
+            }
+            fs::rename(path, rotated(1))?;
+        }
+
+        self.file = open_append(path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+fn open_append(path: &Path) -> io::Result<File> {
+    if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+        fs::create_dir_all(parent)?;
+    }
+    OpenOptions::new().create(true).append(true).open(path)
+}
@@ -1 +1,2 @@
+pub mod audit;
 pub mod logger;
\ No newline at end of file
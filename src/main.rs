@@ -6,17 +6,27 @@ mod waf;
 mod web;
 
 use cli::cli::Cli;
-use config::config::Config;
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     // Parse CLI arguments
     let cli = Cli::parse();
     
-    // Load config
-    let config = Config::load();
+    if cli.is_config_check() {
+        return cli.check_config();
+    }
+
+    // Load and validate config
+    let config = match cli.load_config() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("✗ {}", e);
+            std::process::exit(1);
+        }
+    };
     
     // Initialize logger
     logger::logger::init_tracing(&config.tracing);
+    logger::audit::init_audit_log(&config);
     
     // Execute command
     cli.execute(config)?;
@@ -0,0 +1,189 @@
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Weak};
+use std::time::{Duration, SystemTime};
+
+use arc_swap::ArcSwap;
+use parking_lot::Mutex;
+use tracing::{debug, info, warn};
+
+use crate::config::config::{AccessListConfig, Config};
+
+// Как часто проверяются изменения файлов списков
+const WATCH_INTERVAL: Duration = Duration::from_secs(5);
+
+// Адрес или сеть: "10.0.0.0/8", "2001:db8::/32", "192.0.2.1"
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix: u8,
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let network: IpAddr = addr
+            .trim()
+            .parse()
+            .map_err(|e| format!("invalid address '{}': {}", s, e))?;
+        let max = if network.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .trim()
+                .parse::<u8>()
+                .ok()
+                .filter(|p| *p <= max)
+                .ok_or_else(|| format!("invalid prefix length in '{}'", s))?,
+            None => max,
+        };
+        Ok(Self { network, prefix })
+    }
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        // IPv4-клиенты на dual-stack сокете приходят как ::ffff:a.b.c.d
+        match (self.network, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+// Разбирает записи списка; пустые строки и комментарии "#" пропускаются
+pub fn parse_entries<'a>(entries: impl IntoIterator<Item = &'a str>) -> Result<Vec<Cidr>, String> {
+    entries
+        .into_iter()
+        .map(|line| line.split('#').next().unwrap_or("").trim())
+        .filter(|line| !line.is_empty())
+        .map(Cidr::from_str)
+        .collect()
+}
+
+pub fn load_file(path: &Path) -> Result<Vec<Cidr>, String> {
+    let content = std::fs::read_to_string(path).map_err(|e| format!("cannot read '{}': {}", path.display(), e))?;
+    parse_entries(content.lines()).map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+// Решение по списку для адреса клиента
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AccessDecision {
+    Allow,
+    Deny,
+    // Адреса нет ни в одном списке
+    Unlisted,
+}
+
+// Записи из конфига плюс содержимое файла, которое подменяется при его изменении
+struct CidrSet {
+    inline: Vec<Cidr>,
+    file: Option<PathBuf>,
+    loaded: ArcSwap<Vec<Cidr>>,
+    modified: Mutex<Option<SystemTime>>,
+}
+
+impl CidrSet {
+    fn new(entries: &[String], file: Option<PathBuf>) -> Result<Self, String> {
+        let inline = parse_entries(entries.iter().map(String::as_str))?;
+        let set = Self {
+            inline,
+            file,
+            loaded: ArcSwap::from_pointee(Vec::new()),
+            modified: Mutex::new(None),
+        };
+        if let Some(path) = &set.file {
+            set.loaded.store(Arc::new(load_file(path)?));
+            *set.modified.lock() = modified(path);
+        }
+        Ok(set)
+    }
+
+    fn contains(&self, ip: IpAddr) -> bool {
+        self.inline.iter().any(|cidr| cidr.contains(ip)) || self.loaded.load().iter().any(|cidr| cidr.contains(ip))
+    }
+
+    // Перечитывает файл, если он изменился; при ошибке остаётся прежний список
+    fn refresh(&self) {
+        let Some(path) = &self.file else {
+            return;
+        };
+        let current = modified(path);
+        if current.is_none() || *self.modified.lock() == current {
+            return;
+        }
+
+        match load_file(path) {
+            Ok(entries) => {
+                info!(file = %path.display(), entries = entries.len(), "Access list reloaded");
+                self.loaded.store(Arc::new(entries));
+            }
+            Err(e) => warn!(error = %e, "Failed to reload access list, keeping previous entries"),
+        }
+        *self.modified.lock() = current;
+    }
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+// Списки доступа сервера или upstream'а
+pub struct AccessList {
+    allow: CidrSet,
+    deny: CidrSet,
+    pub bypass_waf: bool,
+}
+
+impl AccessList {
+    pub fn new(access: &AccessListConfig, config: &Config) -> Result<Self, String> {
+        Ok(Self {
+            allow: CidrSet::new(&access.allow, access.allow_file.as_deref().map(|f| config.resolve_path(f)))?,
+            deny: CidrSet::new(&access.deny, access.deny_file.as_deref().map(|f| config.resolve_path(f)))?,
+            bypass_waf: access.allow_bypass_waf,
+        })
+    }
+
+    pub fn check(&self, ip: Option<IpAddr>) -> AccessDecision {
+        let Some(ip) = ip else {
+            return AccessDecision::Unlisted;
+        };
+        if self.allow.contains(ip) {
+            AccessDecision::Allow
+        } else if self.deny.contains(ip) {
+            AccessDecision::Deny
+        } else {
+            AccessDecision::Unlisted
+        }
+    }
+
+    pub fn has_files(&self) -> bool {
+        self.allow.file.is_some() || self.deny.file.is_some()
+    }
+}
+
+// Следит за файлами списков, пока список используется текущим конфигом
+pub async fn watch_access_list(name: String, list: Weak<AccessList>) {
+    let mut interval = tokio::time::interval(WATCH_INTERVAL);
+    loop {
+        interval.tick().await;
+        let Some(list) = list.upgrade() else {
+            debug!(name = %name, "Access list removed, stopping watcher");
+            return;
+        };
+        list.allow.refresh();
+        list.deny.refresh();
+    }
+}
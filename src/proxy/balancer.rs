@@ -0,0 +1,253 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use parking_lot::Mutex;
+use pingora::http::RequestHeader;
+
+use crate::config::config::{HealthCheckConfig, LbMethod, UpstreamConfig};
+use crate::proxy::circuit_breaker::CircuitBreaker;
+use crate::proxy::health::BackendHealth;
+
+// Количество точек на кольце consistent hashing на единицу веса
+const RING_POINTS_PER_WEIGHT: u32 = 160;
+
+// Бэкенд upstream'а
+pub struct Backend {
+    pub addr: String,
+    pub weight: u32,
+    pub health: BackendHealth,
+    pub breaker: CircuitBreaker,
+    active_connections: AtomicUsize,
+}
+
+impl Backend {
+    pub fn new(addr: &str, weight: u32, health: BackendHealth, breaker: CircuitBreaker) -> Self {
+        Self {
+            addr: addr.to_string(),
+            weight: weight.max(1),
+            health,
+            breaker,
+            active_connections: AtomicUsize::new(0),
+        }
+    }
+
+    // Здоров и не отсечён circuit breaker'ом
+    pub fn is_available(&self) -> bool {
+        self.health.is_available() && self.breaker.is_available()
+    }
+
+    pub fn active_connections(&self) -> usize {
+        self.active_connections.load(Ordering::Relaxed)
+    }
+}
+
+// Удерживает счётчик активных соединений бэкенда, пока жив запрос
+pub struct BackendGuard {
+    pub backend: Arc<Backend>,
+}
+
+impl BackendGuard {
+    pub fn new(backend: Arc<Backend>) -> Self {
+        backend.active_connections.fetch_add(1, Ordering::Relaxed);
+        Self { backend }
+    }
+}
+
+impl Drop for BackendGuard {
+    fn drop(&mut self) {
+        self.backend.active_connections.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+pub struct LoadBalancer {
+    method: LbMethod,
+    hash_header: Option<String>,
+    host: String,
+    health_check: Option<HealthCheckConfig>,
+    backends: Vec<Arc<Backend>>,
+    rr_counter: AtomicUsize,
+    // Текущие веса для smooth weighted round-robin (как в nginx)
+    current_weights: Mutex<Vec<i64>>,
+    // Отсортированное кольцо (hash, индекс бэкенда)
+    ring: Vec<(u64, usize)>,
+}
+
+impl LoadBalancer {
+    pub fn new(upstream: &UpstreamConfig) -> Self {
+        let backends: Vec<Arc<Backend>> = upstream
+            .addrs
+            .iter()
+            .enumerate()
+            .map(|(i, addr)| {
+                let weight = upstream.weights.get(i).copied().unwrap_or(1);
+                let health = BackendHealth::new(upstream.passive_health.clone());
+                let breaker = CircuitBreaker::new(upstream.circuit_breaker.clone());
+                Arc::new(Backend::new(addr, weight, health, breaker))
+            })
+            .collect();
+
+        let ring = match upstream.lb_method {
+            LbMethod::IpHash | LbMethod::HeaderHash => Self::build_ring(&backends),
+            _ => Vec::new(),
+        };
+
+        Self {
+            method: upstream.lb_method,
+            hash_header: upstream.hash_header.clone(),
+            host: upstream.sni.clone(),
+            health_check: upstream.health_check.clone(),
+            current_weights: Mutex::new(vec![0; backends.len()]),
+            backends,
+            rr_counter: AtomicUsize::new(0),
+            ring,
+        }
+    }
+
+    fn build_ring(backends: &[Arc<Backend>]) -> Vec<(u64, usize)> {
+        let mut ring = Vec::new();
+        for (idx, backend) in backends.iter().enumerate() {
+            for point in 0..backend.weight * RING_POINTS_PER_WEIGHT {
+                ring.push((hash_of(&format!("{}-{}", backend.addr, point)), idx));
+            }
+        }
+        ring.sort_unstable();
+        ring
+    }
+
+    pub fn backends(&self) -> &[Arc<Backend>] {
+        &self.backends
+    }
+
+    pub fn method(&self) -> LbMethod {
+        self.method
+    }
+
+    pub fn host(&self) -> &str {
+        &self.host
+    }
+
+    pub fn health_check(&self) -> Option<&HealthCheckConfig> {
+        self.health_check.as_ref()
+    }
+
+    // Выбирает доступный бэкенд для запроса согласно lb_method. При повторе
+    // бэкенды из tried пропускаются, если есть другие доступные
+    pub fn select(&self, req: &RequestHeader, client_ip: &str, tried: &[String]) -> Option<Arc<Backend>> {
+        if !self.backends.iter().any(|b| b.is_available()) {
+            return None;
+        }
+        let exclude = if self.backends.iter().any(|b| b.is_available() && !tried.contains(&b.addr)) {
+            tried
+        } else {
+            &[]
+        };
+
+        let idx = match self.method {
+            LbMethod::RoundRobin => self.next_round_robin(exclude),
+            LbMethod::Weighted => self.next_weighted(exclude),
+            LbMethod::LeastConn => self.next_least_conn(exclude),
+            LbMethod::IpHash => self.next_hashed(client_ip, exclude),
+            LbMethod::HeaderHash => {
+                let key = self
+                    .hash_header
+                    .as_deref()
+                    .and_then(|name| req.headers.get(name))
+                    .and_then(|v| v.to_str().ok());
+                match key {
+                    Some(key) => self.next_hashed(key, exclude),
+                    // Без заголовка хешируем по IP клиента
+                    None => self.next_hashed(client_ip, exclude),
+                }
+            }
+        };
+
+        self.backends.get(idx).cloned()
+    }
+
+    // Все бэкенды отсечены circuit breaker'ом; None, если хотя бы один принимает запросы.
+    // Иначе - через сколько откроется первый
+    pub fn breakers_open(&self) -> Option<Duration> {
+        if self.backends.is_empty() || self.backends.iter().any(|b| b.breaker.is_available()) {
+            return None;
+        }
+        self.backends
+            .iter()
+            .map(|b| b.breaker.retry_after().unwrap_or_default())
+            .min()
+    }
+
+    fn is_available(&self, idx: usize, exclude: &[String]) -> bool {
+        let backend = &self.backends[idx];
+        backend.is_available() && !exclude.contains(&backend.addr)
+    }
+
+    fn next_round_robin(&self, exclude: &[String]) -> usize {
+        let len = self.backends.len();
+        let start = self.rr_counter.fetch_add(1, Ordering::Relaxed);
+        (0..len)
+            .map(|offset| (start + offset) % len)
+            .find(|&i| self.is_available(i, exclude))
+            .unwrap_or(start % len)
+    }
+
+    fn next_weighted(&self, exclude: &[String]) -> usize {
+        let mut current = self.current_weights.lock();
+        let mut total: i64 = 0;
+        let mut best: Option<usize> = None;
+
+        for (i, backend) in self.backends.iter().enumerate() {
+            if !self.is_available(i, exclude) {
+                continue;
+            }
+            current[i] += backend.weight as i64;
+            total += backend.weight as i64;
+            if best.is_none_or(|b| current[i] > current[b]) {
+                best = Some(i);
+            }
+        }
+
+        let best = best.unwrap_or(0);
+        current[best] -= total;
+        best
+    }
+
+    fn next_least_conn(&self, exclude: &[String]) -> usize {
+        // Сравниваем active/weight без деления: a1 * w2 < a2 * w1
+        let start = self.next_round_robin(exclude);
+        let len = self.backends.len();
+        let mut best = start;
+        for offset in 1..len {
+            let i = (start + offset) % len;
+            if !self.is_available(i, exclude) {
+                continue;
+            }
+            let candidate = &self.backends[i];
+            let current = &self.backends[best];
+            let candidate_load = candidate.active_connections() * current.weight as usize;
+            let current_load = current.active_connections() * candidate.weight as usize;
+            if candidate_load < current_load {
+                best = i;
+            }
+        }
+        best
+    }
+
+    fn next_hashed(&self, key: &str, exclude: &[String]) -> usize {
+        let hash = hash_of(key);
+        let pos = self.ring.partition_point(|(point, _)| *point < hash);
+        // Идём по кольцу дальше, пропуская недоступные бэкенды
+        (0..self.ring.len())
+            .map(|offset| self.ring[(pos + offset) % self.ring.len()].1)
+            .find(|&idx| self.is_available(idx, exclude))
+            .unwrap_or(self.ring[pos % self.ring.len()].1)
+    }
+}
+
+fn hash_of(key: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    hasher.finish()
+}
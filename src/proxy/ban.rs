@@ -0,0 +1,148 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::LazyLock;
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Utc};
+use parking_lot::{Mutex, RwLock};
+use tracing::{info, warn};
+
+use crate::config::config::BanConfig;
+
+// После скольких отслеживаемых клиентов вычищаем устаревшие записи
+const PRUNE_THRESHOLD: usize = 10_000;
+// Длительность ручного бана, если секция [ban] не задана
+const DEFAULT_BAN_SECS: u64 = 600;
+
+static BAN_LIST: LazyLock<BanList> = LazyLock::new(BanList::new);
+
+pub fn ban_list() -> &'static BanList {
+    &BAN_LIST
+}
+
+pub struct BanEntry {
+    pub until: Instant,
+    pub banned_at: DateTime<Utc>,
+    pub reason: String,
+}
+
+// Список временно заблокированных IP в духе fail2ban; общий для всех серверов
+pub struct BanList {
+    config: RwLock<Option<BanConfig>>,
+    // Время недавних нарушений по каждому IP
+    offenders: Mutex<HashMap<String, VecDeque<Instant>>>,
+    bans: Mutex<HashMap<String, BanEntry>>,
+}
+
+impl BanList {
+    fn new() -> Self {
+        Self {
+            config: RwLock::new(None),
+            offenders: Mutex::new(HashMap::new()),
+            bans: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Применяется при старте и перезагрузке конфига; текущие баны сохраняются
+    pub fn configure(&self, config: Option<BanConfig>) {
+        let config = config.filter(|cfg| cfg.enabled);
+        if config.is_none() {
+            self.offenders.lock().clear();
+        }
+        *self.config.write() = config;
+    }
+
+    // Оставшееся время бана, если IP заблокирован
+    pub fn banned_for(&self, ip: &str) -> Option<Duration> {
+        let mut bans = self.bans.lock();
+        let remaining = bans.get(ip)?.until.checked_duration_since(Instant::now());
+        if remaining.is_none() {
+            bans.remove(ip);
+            info!(client_ip = %ip, "Ban expired");
+        }
+        remaining
+    }
+
+    // Учитывает нарушения клиента; возвращает true, если клиент только что заблокирован
+    pub fn record_violations(&self, ip: &str, count: usize) -> bool {
+        let Some(config) = self.config.read().clone() else {
+            return false;
+        };
+        if count == 0 || ip.is_empty() {
+            return false;
+        }
+
+        let now = Instant::now();
+        let window = Duration::from_secs(config.window_secs);
+        let violations = {
+            let mut offenders = self.offenders.lock();
+            if offenders.len() > PRUNE_THRESHOLD {
+                offenders.retain(|_, times| times.back().is_some_and(|t| now.duration_since(*t) < window));
+            }
+
+            let times = offenders.entry(ip.to_string()).or_default();
+            while times.front().is_some_and(|t| now.duration_since(*t) >= window) {
+                times.pop_front();
+            }
+            times.extend(std::iter::repeat_n(now, count));
+            times.len()
+        };
+
+        if violations < config.max_violations as usize {
+            return false;
+        }
+
+        self.offenders.lock().remove(ip);
+        let reason = format!("{} violations in {}s", violations, config.window_secs);
+        warn!(client_ip = %ip, ban_secs = config.ban_secs, reason = %reason, "Client banned");
+        self.ban(ip, Duration::from_secs(config.ban_secs), reason);
+        true
+    }
+
+    // Длительность бана из конфига
+    pub fn ban_duration(&self) -> Duration {
+        Duration::from_secs(self.config.read().as_ref().map_or(DEFAULT_BAN_SECS, |cfg| cfg.ban_secs))
+    }
+
+    pub fn ban(&self, ip: &str, duration: Duration, reason: String) {
+        self.bans.lock().insert(
+            ip.to_string(),
+            BanEntry {
+                until: Instant::now() + duration,
+                banned_at: Utc::now(),
+                reason,
+            },
+        );
+    }
+
+    pub fn unban(&self, ip: &str) -> bool {
+        self.offenders.lock().remove(ip);
+        self.bans.lock().remove(ip).is_some()
+    }
+
+    // Текстовый список для admin API
+    pub fn describe(&self) -> String {
+        let now = Instant::now();
+        let mut bans = self.bans.lock();
+        bans.retain(|_, entry| entry.until > now);
+
+        let mut lines: Vec<String> = bans
+            .iter()
+            .map(|(ip, entry)| {
+                format!(
+                    "{} remaining={}s banned_at={} reason=\"{}\"",
+                    ip,
+                    entry.until.duration_since(now).as_secs(),
+                    entry.banned_at.to_rfc3339(),
+                    entry.reason
+                )
+            })
+            .collect();
+        lines.sort();
+
+        if lines.is_empty() {
+            "No banned clients\n".to_string()
+        } else {
+            lines.join("\n") + "\n"
+        }
+    }
+}
@@ -0,0 +1,222 @@
+use bytes::Bytes;
+use chrono::{SecondsFormat, Utc};
+use pingora::http::{RequestHeader, ResponseHeader};
+use pingora::proxy::Session;
+use pingora::Result;
+
+use crate::config::config::{BlockPageConfig, Config};
+
+const DEFAULT_STATUS: u16 = 403;
+
+const DEFAULT_HTML: &str = r#"<!DOCTYPE html>
+<html>
+<head><meta charset="utf-8"><title>{{status}} Request blocked</title></head>
+<body>
+<h1>Request blocked</h1>
+<p>The request was blocked by the web application firewall.</p>
+<p>Request ID: {{request_id}}<br>Rule: {{rule_id}}<br>Time: {{timestamp}}</p>
+</body>
+</html>
+"#;
+
+const DEFAULT_JSON: &str = r#"{"error":"request_blocked","status":{{status}},"request_id":"{{request_id}}","rule_id":"{{rule_id}}","timestamp":"{{timestamp}}"}
+"#;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Format {
+    Html,
+    Json,
+}
+
+// Что подставляется в шаблон
+pub struct BlockInfo<'a> {
+    pub request_id: &'a str,
+    // Правило ModSecurity; None для блокировок по IP, бану и лимитам
+    pub rule_id: Option<u32>,
+}
+
+// Ответ на заблокированный запрос сервера или upstream'а
+pub struct BlockPage {
+    status: Option<u16>,
+    html: String,
+    json: String,
+}
+
+impl Default for BlockPage {
+    fn default() -> Self {
+        Self {
+            status: None,
+            html: DEFAULT_HTML.to_string(),
+            json: DEFAULT_JSON.to_string(),
+        }
+    }
+}
+
+impl BlockPage {
+    pub fn new(page: &BlockPageConfig, config: &Config) -> Result<Self, String> {
+        let load = |file: &Option<String>, default: &str| match file {
+            Some(file) => {
+                let path = config.resolve_path(file);
+                std::fs::read_to_string(&path).map_err(|e| format!("cannot read '{}': {}", path.display(), e))
+            }
+            None => Ok(default.to_string()),
+        };
+        Ok(Self {
+            status: page.status,
+            html: load(&page.html_file, DEFAULT_HTML)?,
+            json: load(&page.json_file, DEFAULT_JSON)?,
+        })
+    }
+
+    // Код ответа при блокировке WAF: из конфига, иначе 4xx/5xx из intervention, иначе 403.
+    // 3xx используется только для редиректа с адресом (redirect_location)
+    pub fn waf_status(&self, intervention_status: u16) -> u16 {
+        self.status.unwrap_or(match intervention_status {
+            400..=599 => intervention_status,
+            _ => DEFAULT_STATUS,
+        })
+    }
+
+    // Тело и Content-Type под заголовок Accept запроса
+    pub fn render(&self, req: &RequestHeader, status: u16, info: &BlockInfo) -> (&'static str, Bytes) {
+        let format = negotiate(req);
+        let (template, content_type) = match format {
+            Format::Html => (&self.html, "text/html; charset=utf-8"),
+            Format::Json => (&self.json, "application/json"),
+        };
+        let escape = |value: &str| match format {
+            Format::Html => escape_html(value),
+            Format::Json => escape_json(value),
+        };
+
+        let rule_id = info.rule_id.map(|id| id.to_string()).unwrap_or_default();
+        let body = template
+            .replace("{{status}}", &status.to_string())
+            .replace("{{request_id}}", &escape(info.request_id))
+            .replace("{{rule_id}}", &rule_id)
+            .replace("{{timestamp}}", &Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true));
+        (content_type, Bytes::from(body))
+    }
+
+    // Отправляет страницу блокировки; headers - дополнительные заголовки, например Retry-After
+    pub async fn respond(
+        &self,
+        session: &mut Session,
+        status: u16,
+        info: &BlockInfo<'_>,
+        headers: &[(&'static str, String)],
+    ) -> Result<()> {
+        let (content_type, body) = self.render(session.req_header(), status, info);
+
+        let mut response = ResponseHeader::build(status, Some(3 + headers.len()))?;
+        response.insert_header("Content-Type", content_type)?;
+        response.insert_header("Content-Length", body.len().to_string())?;
+        response.insert_header("Cache-Control", "no-store")?;
+        for (name, value) in headers {
+            response.insert_header(*name, value.as_str())?;
+        }
+
+        let head_only = session.req_header().method.as_str() == "HEAD";
+        session.write_response_header(Box::new(response), head_only).await?;
+        if !head_only {
+            session.write_response_body(Some(body), true).await?;
+        }
+        Ok(())
+    }
+}
+
+// JSON, если клиент предпочитает его HTML; при равенстве и без Accept - HTML
+fn negotiate(req: &RequestHeader) -> Format {
+    let Some(accept) = req.headers.get("accept").and_then(|v| v.to_str().ok()) else {
+        return Format::Html;
+    };
+
+    let mut html: f32 = 0.0;
+    let mut json: f32 = 0.0;
+    for range in accept.split(',') {
+        let mut parts = range.split(';');
+        let media = parts.next().unwrap_or("").trim().to_lowercase();
+        let q = parts
+            .filter_map(|p| p.trim().strip_prefix("q="))
+            .find_map(|q| q.parse::<f32>().ok())
+            .unwrap_or(1.0);
+
+        match media.as_str() {
+            "text/html" | "application/xhtml+xml" => html = html.max(q),
+            "application/json" | "application/problem+json" => json = json.max(q),
+            media if media.ends_with("+json") => json = json.max(q),
+            // Маски учитываются с меньшим приоритетом, чем точные типы
+            "*/*" | "text/*" => html = html.max(q * 0.5),
+            "application/*" => json = json.max(q * 0.5),
+            _ => {}
+        }
+    }
+    if json > html {
+        Format::Json
+    } else {
+        Format::Html
+    }
+}
+
+fn escape_html(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn escape_json(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if c.is_control() => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn waf_status_uses_error_statuses_from_intervention() {
+        let page = BlockPage::default();
+        assert_eq!(page.waf_status(406), 406);
+        assert_eq!(page.waf_status(429), 429);
+        assert_eq!(page.waf_status(503), 503);
+    }
+
+    #[test]
+    fn waf_status_falls_back_to_403() {
+        let page = BlockPage::default();
+        // Правило не задало код
+        assert_eq!(page.waf_status(0), 403);
+        // Страница блокировки не уходит с 2xx
+        assert_eq!(page.waf_status(200), 403);
+        // 3xx без адреса редиректа - ответ без Location
+        assert_eq!(page.waf_status(302), 403);
+        // Не HTTP-коды
+        assert_eq!(page.waf_status(100), 403);
+        assert_eq!(page.waf_status(600), 403);
+        assert_eq!(page.waf_status(999), 403);
+    }
+
+    #[test]
+    fn waf_status_prefers_configured_status() {
+        let page = BlockPage { status: Some(451), ..BlockPage::default() };
+        assert_eq!(page.waf_status(406), 451);
+        assert_eq!(page.waf_status(0), 451);
+    }
+}
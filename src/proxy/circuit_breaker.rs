@@ -0,0 +1,194 @@
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+
+use crate::config::config::CircuitBreakerConfig;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BreakerState {
+    // Трафик идёт как обычно, ошибки считаются в окне
+    Closed,
+    // Бэкенд исключён до истечения open_secs
+    Open,
+    // Пропускается half_open_requests пробных запросов
+    HalfOpen,
+}
+
+impl BreakerState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            BreakerState::Closed => "closed",
+            BreakerState::Open => "open",
+            BreakerState::HalfOpen => "half-open",
+        }
+    }
+}
+
+struct BreakerInner {
+    state: BreakerState,
+    // Момент последней смены состояния
+    changed_at: Instant,
+    window_start: Instant,
+    requests: u32,
+    failures: u32,
+    // Пробные запросы в half-open: отправленные и успешные
+    probes: u32,
+    probe_successes: u32,
+}
+
+impl BreakerInner {
+    fn switch(&mut self, state: BreakerState, now: Instant) {
+        self.state = state;
+        self.changed_at = now;
+        self.window_start = now;
+        self.requests = 0;
+        self.failures = 0;
+        self.probes = 0;
+        self.probe_successes = 0;
+    }
+}
+
+// Circuit breaker одного бэкенда; без конфига всегда закрыт
+pub struct CircuitBreaker {
+    config: Option<CircuitBreakerConfig>,
+    inner: Mutex<BreakerInner>,
+}
+
+impl CircuitBreaker {
+    pub fn new(config: Option<CircuitBreakerConfig>) -> Self {
+        let now = Instant::now();
+        Self {
+            config,
+            inner: Mutex::new(BreakerInner {
+                state: BreakerState::Closed,
+                changed_at: now,
+                window_start: now,
+                requests: 0,
+                failures: 0,
+                probes: 0,
+                probe_successes: 0,
+            }),
+        }
+    }
+
+    // Текущее состояние; open переходит в half-open по истечении open_secs
+    fn current<'a>(&self, config: &CircuitBreakerConfig, inner: &'a mut BreakerInner) -> &'a mut BreakerInner {
+        let now = Instant::now();
+        let open_for = Duration::from_secs(config.open_secs);
+        match inner.state {
+            BreakerState::Open if now.duration_since(inner.changed_at) >= open_for => {
+                inner.switch(BreakerState::HalfOpen, now);
+            }
+            // Исход пробных запросов так и не пришёл (клиент ушёл) - даём новые попытки
+            BreakerState::HalfOpen
+                if inner.probes >= config.half_open_requests
+                    && now.duration_since(inner.changed_at) >= open_for =>
+            {
+                inner.switch(BreakerState::HalfOpen, now);
+            }
+            _ => {}
+        }
+        inner
+    }
+
+    // Можно ли выбрать бэкенд для нового запроса
+    pub fn is_available(&self) -> bool {
+        let Some(config) = &self.config else {
+            return true;
+        };
+        let mut inner = self.inner.lock();
+        let inner = self.current(config, &mut inner);
+        match inner.state {
+            BreakerState::Closed => true,
+            BreakerState::Open => false,
+            BreakerState::HalfOpen => inner.probes < config.half_open_requests,
+        }
+    }
+
+    // Бэкенд выбран для запроса; в half-open занимает пробный слот
+    pub fn acquire(&self) {
+        let Some(config) = &self.config else {
+            return;
+        };
+        let mut inner = self.inner.lock();
+        let inner = self.current(config, &mut inner);
+        if inner.state == BreakerState::HalfOpen {
+            inner.probes += 1;
+        }
+    }
+
+    // Результат запроса к бэкенду; возвращает новое состояние, если оно сменилось
+    pub fn record(&self, success: bool, latency: Option<Duration>) -> Option<BreakerState> {
+        let config = self.config.as_ref()?;
+        let slow = config
+            .slow_ms
+            .zip(latency)
+            .is_some_and(|(slow_ms, latency)| latency > Duration::from_millis(slow_ms));
+        let failed = !success || slow;
+
+        let now = Instant::now();
+        let mut inner = self.inner.lock();
+        let inner = self.current(config, &mut inner);
+        match inner.state {
+            BreakerState::Closed => {
+                if now.duration_since(inner.window_start) >= Duration::from_secs(config.window_secs) {
+                    inner.window_start = now;
+                    inner.requests = 0;
+                    inner.failures = 0;
+                }
+                inner.requests += 1;
+                if failed {
+                    inner.failures += 1;
+                }
+                let rate = inner.failures as f64 / inner.requests as f64;
+                if inner.requests >= config.min_requests && rate >= config.error_rate {
+                    inner.switch(BreakerState::Open, now);
+                    return Some(BreakerState::Open);
+                }
+                None
+            }
+            BreakerState::HalfOpen => {
+                if failed {
+                    inner.switch(BreakerState::Open, now);
+                    return Some(BreakerState::Open);
+                }
+                inner.probe_successes += 1;
+                if inner.probe_successes >= config.half_open_requests {
+                    inner.switch(BreakerState::Closed, now);
+                    return Some(BreakerState::Closed);
+                }
+                None
+            }
+            // Ответы на запросы, отправленные до открытия
+            BreakerState::Open => None,
+        }
+    }
+
+    // Сколько ещё бэкенд будет исключён
+    pub fn retry_after(&self) -> Option<Duration> {
+        let config = self.config.as_ref()?;
+        let mut inner = self.inner.lock();
+        let inner = self.current(config, &mut inner);
+        (inner.state == BreakerState::Open)
+            .then(|| Duration::from_secs(config.open_secs).saturating_sub(inner.changed_at.elapsed()))
+    }
+
+    pub fn describe(&self) -> String {
+        let Some(config) = &self.config else {
+            return "disabled".to_string();
+        };
+        let mut inner = self.inner.lock();
+        let inner = self.current(config, &mut inner);
+        match inner.state {
+            BreakerState::Closed => format!("closed requests={} failures={}", inner.requests, inner.failures),
+            BreakerState::Open => format!(
+                "open retry_in={}s",
+                Duration::from_secs(config.open_secs).saturating_sub(inner.changed_at.elapsed()).as_secs()
+            ),
+            BreakerState::HalfOpen => format!(
+                "half-open probes={}/{} successes={}",
+                inner.probes, config.half_open_requests, inner.probe_successes
+            ),
+        }
+    }
+}
@@ -0,0 +1,120 @@
+use std::net::{IpAddr, SocketAddr};
+
+use pingora::http::RequestHeader;
+use pingora::proxy::Session;
+
+use crate::config::config::{RealIpSource, ServerConfig};
+use crate::proxy::access::{parse_entries, Cidr};
+use crate::proxy::proxy_protocol;
+
+// Определяет реальный адрес клиента за доверенными прокси
+pub struct ClientIpResolver {
+    trusted: Vec<Cidr>,
+    source: RealIpSource,
+}
+
+impl ClientIpResolver {
+    pub fn new(server: &ServerConfig) -> Result<Self, String> {
+        Ok(Self {
+            trusted: parse_entries(server.trusted_proxies.iter().map(String::as_str))?,
+            source: server.real_ip_source,
+        })
+    }
+
+    fn is_trusted(&self, ip: IpAddr) -> bool {
+        self.trusted.iter().any(|cidr| cidr.contains(ip))
+    }
+
+    // Непосредственный отправитель HTTP-запроса; для PROXY protocol - адрес из заголовка
+    pub fn peer(&self, session: &Session) -> Option<SocketAddr> {
+        let peer = *session.client_addr()?.as_inet()?;
+        if self.source != RealIpSource::ProxyProtocol {
+            return Some(peer);
+        }
+        let client = match proxy_protocol::lookup(&peer) {
+            Some(conn) => match conn.source {
+                Some(source) if self.is_trusted(conn.peer.ip()) => source,
+                _ => conn.peer,
+            },
+            None => peer,
+        };
+        Some(client)
+    }
+
+    // Можно ли верить заголовкам X-Forwarded-* и X-Request-Id этого запроса
+    pub fn is_trusted_peer(&self, session: &Session) -> bool {
+        self.peer(session).is_some_and(|peer| self.is_trusted(peer.ip()))
+    }
+
+    // Адрес клиента; порт 0, если адрес взят из заголовка
+    pub fn resolve(&self, session: &Session) -> Option<SocketAddr> {
+        let peer = self.peer(session)?;
+        let req = session.req_header();
+
+        let client = match self.source {
+            RealIpSource::ProxyProtocol => peer,
+            RealIpSource::XForwardedFor => {
+                let values = header_values(req, "x-forwarded-for");
+                self.walk_chain(peer, values.iter().flat_map(|v| v.split(',')).collect())
+            }
+            RealIpSource::XRealIp => match header_values(req, "x-real-ip").first() {
+                Some(value) if self.is_trusted(peer.ip()) => parse_addr(value).unwrap_or(peer),
+                _ => peer,
+            },
+            RealIpSource::Forwarded => {
+                let values = header_values(req, "forwarded");
+                let hops = values
+                    .iter()
+                    .flat_map(|v| v.split(','))
+                    .map(|element| {
+                        element
+                            .split(';')
+                            .filter_map(|pair| pair.split_once('='))
+                            .find(|(key, _)| key.trim().eq_ignore_ascii_case("for"))
+                            .map_or("", |(_, value)| value)
+                    })
+                    .collect();
+                self.walk_chain(peer, hops)
+            }
+        };
+        Some(client)
+    }
+
+    // Идём по цепочке справа налево, пока адреса принадлежат доверенным прокси
+    fn walk_chain(&self, peer: SocketAddr, hops: Vec<&str>) -> SocketAddr {
+        let mut client = peer;
+        for hop in hops.iter().rev() {
+            if !self.is_trusted(client.ip()) {
+                break;
+            }
+            match parse_addr(hop) {
+                Some(addr) => client = addr,
+                None => break,
+            }
+        }
+        client
+    }
+}
+
+fn header_values(req: &RequestHeader, name: &str) -> Vec<String> {
+    req.headers
+        .get_all(name)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .map(|v| v.to_string())
+        .collect()
+}
+
+// "192.0.2.1", "192.0.2.1:4711", "[2001:db8::1]:4711", "\"[2001:db8::1]\""
+fn parse_addr(value: &str) -> Option<SocketAddr> {
+    let value = value.trim().trim_matches('"');
+    if let Ok(addr) = value.parse::<SocketAddr>() {
+        return Some(addr);
+    }
+    value
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .parse::<IpAddr>()
+        .ok()
+        .map(|ip| SocketAddr::new(ip, 0))
+}
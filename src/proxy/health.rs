@@ -0,0 +1,182 @@
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::Weak;
+use std::time::{Duration, Instant};
+
+use futures::future::join_all;
+use parking_lot::Mutex;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+use tracing::{debug, info, warn};
+
+use crate::config::config::{HealthCheckConfig, HealthCheckKind, PassiveHealthConfig};
+use crate::proxy::balancer::{Backend, LoadBalancer};
+
+// Состояние здоровья одного бэкенда
+pub struct BackendHealth {
+    healthy: AtomicBool,
+    probe_successes: AtomicU32,
+    probe_failures: AtomicU32,
+    passive_failures: AtomicU32,
+    ejected_until: Mutex<Option<Instant>>,
+    passive: Option<PassiveHealthConfig>,
+}
+
+impl BackendHealth {
+    pub fn new(passive: Option<PassiveHealthConfig>) -> Self {
+        Self {
+            healthy: AtomicBool::new(true),
+            probe_successes: AtomicU32::new(0),
+            probe_failures: AtomicU32::new(0),
+            passive_failures: AtomicU32::new(0),
+            ejected_until: Mutex::new(None),
+            passive,
+        }
+    }
+
+    // Бэкенд может принимать трафик: активная проверка успешна и он не исключён пассивно
+    pub fn is_available(&self) -> bool {
+        self.healthy.load(Ordering::Relaxed) && !self.is_ejected()
+    }
+
+    pub fn is_healthy(&self) -> bool {
+        self.healthy.load(Ordering::Relaxed)
+    }
+
+    pub fn is_ejected(&self) -> bool {
+        let mut ejected_until = self.ejected_until.lock();
+        match *ejected_until {
+            Some(until) if Instant::now() < until => true,
+            Some(_) => {
+                *ejected_until = None;
+                false
+            }
+            None => false,
+        }
+    }
+
+    pub fn passive_failures(&self) -> u32 {
+        self.passive_failures.load(Ordering::Relaxed)
+    }
+
+    // Результат активной проверки с учётом порогов
+    pub fn record_probe(&self, success: bool, config: &HealthCheckConfig) -> bool {
+        if success {
+            self.probe_failures.store(0, Ordering::Relaxed);
+            let successes = self.probe_successes.fetch_add(1, Ordering::Relaxed) + 1;
+            if successes >= config.healthy_threshold && !self.healthy.swap(true, Ordering::Relaxed) {
+                return true;
+            }
+        } else {
+            self.probe_successes.store(0, Ordering::Relaxed);
+            let failures = self.probe_failures.fetch_add(1, Ordering::Relaxed) + 1;
+            if failures >= config.unhealthy_threshold && self.healthy.swap(false, Ordering::Relaxed) {
+                return true;
+            }
+        }
+        false
+    }
+
+    // Ошибка соединения или 5xx от бэкенда
+    pub fn report_failure(&self) -> bool {
+        let Some(passive) = &self.passive else {
+            return false;
+        };
+
+        let failures = self.passive_failures.fetch_add(1, Ordering::Relaxed) + 1;
+        if failures >= passive.max_fails {
+            self.passive_failures.store(0, Ordering::Relaxed);
+            *self.ejected_until.lock() =
+                Some(Instant::now() + Duration::from_secs(passive.fail_timeout_secs));
+            return true;
+        }
+        false
+    }
+
+    pub fn report_success(&self) {
+        self.passive_failures.store(0, Ordering::Relaxed);
+    }
+}
+
+// Запускает активные проверки для upstream, если они настроены.
+// Задача завершается, когда балансировщик удалён после перезагрузки конфига.
+pub async fn run_health_checks(upstream_name: String, balancer: Weak<LoadBalancer>) {
+    let Some(config) = balancer.upgrade().and_then(|b| b.health_check().cloned()) else {
+        return;
+    };
+
+    info!(
+        upstream = %upstream_name,
+        kind = ?config.kind,
+        interval_secs = config.interval_secs,
+        "Starting active health checks"
+    );
+
+    let mut interval = tokio::time::interval(Duration::from_secs(config.interval_secs.max(1)));
+    loop {
+        interval.tick().await;
+
+        let Some(balancer) = balancer.upgrade() else {
+            info!(upstream = %upstream_name, "Upstream removed, stopping health checks");
+            return;
+        };
+
+        // Бэкенды проверяются параллельно: зависший не задерживает проверку остальных
+        let (config, upstream_name, host) = (&config, &upstream_name, balancer.host());
+        let probes = balancer.backends().iter().map(|backend| async move {
+            let success = probe(backend, config, host).await;
+            debug!(upstream = %upstream_name, backend = %backend.addr, success, "Health probe");
+
+            if backend.health.record_probe(success, config) {
+                if success {
+                    info!(upstream = %upstream_name, backend = %backend.addr, "Backend is healthy again");
+                } else {
+                    warn!(upstream = %upstream_name, backend = %backend.addr, "Backend marked unhealthy");
+                }
+            }
+        });
+        join_all(probes).await;
+    }
+}
+
+async fn probe(backend: &Backend, config: &HealthCheckConfig, host: &str) -> bool {
+    let probe_timeout = Duration::from_millis(config.timeout_ms);
+
+    let mut stream = match timeout(probe_timeout, TcpStream::connect(&backend.addr)).await {
+        Ok(Ok(stream)) => stream,
+        _ => return false,
+    };
+
+    if config.kind == HealthCheckKind::Tcp {
+        return true;
+    }
+
+    let request = format!(
+        "GET {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: centaur-health-check\r\nConnection: close\r\n\r\n",
+        config.path, host
+    );
+
+    let exchange = async {
+        stream.write_all(request.as_bytes()).await?;
+        let mut buf = [0u8; 64];
+        let n = stream.read(&mut buf).await?;
+        Ok::<_, std::io::Error>(String::from_utf8_lossy(&buf[..n]).to_string())
+    };
+
+    let status_line = match timeout(probe_timeout, exchange).await {
+        Ok(Ok(line)) => line,
+        _ => return false,
+    };
+
+    // "HTTP/1.1 200 OK"
+    let status = status_line
+        .split_whitespace()
+        .nth(1)
+        .and_then(|code| code.parse::<u16>().ok());
+
+    match (status, config.expected_status) {
+        (Some(status), Some(expected)) => status == expected,
+        (Some(status), None) => (200..400).contains(&status),
+        (None, _) => false,
+    }
+}
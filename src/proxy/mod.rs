@@ -1,3 +1,17 @@
 pub mod proxy;
+pub mod access;
 pub mod body_inspector;
-pub mod proxy_manager;
\ No newline at end of file
+pub mod balancer;
+pub mod ban;
+pub mod block_page;
+pub mod circuit_breaker;
+pub mod client_ip;
+pub mod health;
+pub mod proxy_manager;
+pub mod proxy_protocol;
+pub mod rate_limit;
+pub mod request_body;
+pub mod request_id;
+pub mod routes;
+pub mod servers;
+pub mod tls;
\ No newline at end of file
@@ -1,19 +1,36 @@
 use std::sync::Arc;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::time::{Duration, Instant};
 
 //use pingora::server::Server;
 //use pingora::proxy::{http_proxy_service, ProxyHttp, Session};
 use pingora::proxy::{ProxyHttp, Session};
 use pingora::upstreams::peer::HttpPeer;
-use pingora::http::HMap;
-use pingora::Result;
+use pingora::http::{RequestHeader, ResponseHeader};
+use pingora::protocols::Digest;
+use pingora::{Error, ErrorSource, ErrorType, Result};
 
 use crate::waf::reloader::SharedWaf;
-use crate::waf::Engine;
-use crate::config::config::{Config, UpstreamConfig};
+use crate::waf::transaction::WafTransaction;
+use crate::waf::{Engine, MatchedRule, WafCheckResult};
+use crate::config::config::{Config, RetryConfig, UpstreamConfig, WafMode};
 use crate::web::api::run_admin_server;
+use crate::web::metrics;
 use crate::proxy::body_inspector::BodyInspector;
-use crate::proxy::proxy_manager::ProxyManager;
+use crate::proxy::balancer::{BackendGuard, LoadBalancer};
+use crate::proxy::access::{AccessDecision, AccessList};
+use crate::proxy::ban::ban_list;
+use crate::proxy::block_page::{BlockInfo, BlockPage};
+use crate::proxy::client_ip::ClientIpResolver;
+use crate::proxy::rate_limit::RateLimiter;
+use crate::proxy::request_body::RequestBody;
+use crate::proxy::request_id::{self, REQUEST_ID_HEADER};
+use crate::proxy::routes::{normalize_path, request_host, HostRouter, Route};
+use crate::proxy::servers::ServerManager;
+use crate::proxy::tls::UpstreamTls;
+use crate::logger::audit::{
+    audit_log, AuditLogger, AuditMessage, AuditRecord, AuditRequest, AuditResponse, AuditTransaction,
+};
 
 use bytes::Bytes;
 use chrono::Utc;
@@ -22,71 +39,178 @@ use tracing::{debug, error, info, warn, instrument};
 
 //use serde::Deserialize;
 
+// Тела ответов каких типов передаются в ModSecurity
+const INSPECTED_RESPONSE_TYPES: &[&str] = &[
+    "text/",
+    "application/json",
+    "application/xml",
+    "application/javascript",
+    "application/xhtml+xml",
+];
+
+const DEFAULT_MAX_RESPONSE_BODY_SIZE: usize = 1024 * 1024;
+
+// Повторяются только запросы, которые бэкенд может безопасно получить дважды
+const IDEMPOTENT_METHODS: &[&str] = &["GET", "HEAD", "OPTIONS", "PUT", "DELETE", "TRACE"];
+
+// Ответ бэкенда из retry.on_status: прерывает проксирование, чтобы повторить запрос
+const RETRY_STATUS_ERROR: ErrorType = ErrorType::Custom("UpstreamRetryStatus");
+
 #[derive(Clone, Debug)]
 pub struct WafViolation {
     pub rule_id: u32,
     pub reason: String,
     pub blocked: bool,
     pub timestamp: chrono::DateTime<Utc>,
-    pub source: String, // "header" или "body"
+    pub source: String, // "header", "body", "response_headers", "response_body" или "rate_limit"
+    pub rules: Vec<MatchedRule>,
 }
 
 // Структура для хранения состояния запроса
 pub struct RequestContext {
-    pub body_inspector: BodyInspector,
+    // Тело запроса: размер, начало для audit log, удержанные до решения WAF фрагменты
+    pub request_body: RequestBody,
     pub upstream_name: Option<String>,
     pub upstream_key: Option<String>,
     pub client_ip: String,
+    // X-Request-Id запроса, общий для логов прокси, ModSecurity и бэкенда
+    pub request_id: String,
+    pub mode: WafMode,
     pub violations: Vec<WafViolation>,
+    pub backend: Option<BackendGuard>,
+    // Исход текущей попытки уже учтён circuit breaker'ом: ошибка после ответа
+    // или при чтении тела не должна засчитываться повторно
+    pub breaker_recorded: bool,
+    // Момент выбора бэкенда, для метрики задержки upstream
+    pub upstream_started: Option<Instant>,
+    // Бэкенды, на которые уже уходил запрос, и число повторов
+    pub tried_backends: Vec<String>,
+    pub retries: u32,
+    // Фаза 2 уже пройдена; при повторе тело отправляется заново без проверки
+    pub request_body_checked: bool,
+    // Одна транзакция ModSecurity на весь запрос
+    pub waf_tx: Option<WafTransaction>,
+    // Состояние проверки ответа
+    pub response_inspector: BodyInspector,
+    pub inspect_response_body: bool,
+    pub response_blocked: bool,
 }
 
 impl RequestContext {
     pub fn new(client_ip: &str) -> Self {
         Self {
-            body_inspector: BodyInspector::new(10 * 1024 * 1024, true), // 10MB по умолчанию
+            request_body: RequestBody::disabled(),
             upstream_name: None,
             upstream_key: None,
             client_ip: client_ip.to_string(),
+            request_id: String::new(),
+            mode: WafMode::Block,
             violations: Vec::new(),
+            backend: None,
+            breaker_recorded: false,
+            upstream_started: None,
+            tried_backends: Vec::new(),
+            retries: 0,
+            request_body_checked: false,
+            waf_tx: None,
+            response_inspector: BodyInspector::new(DEFAULT_MAX_RESPONSE_BODY_SIZE, false),
+            inspect_response_body: false,
+            response_blocked: false,
         }
     }
 }
 
 pub(crate) struct MyProxy {
     waf_engines: HashMap<String, Arc<SharedWaf>>,
+    balancers: HashMap<String, Arc<LoadBalancer>>,
+    rate_limiters: HashMap<String, Arc<RateLimiter>>,
+    server_rate_limiter: Arc<RateLimiter>,
+    access_lists: HashMap<String, Arc<AccessList>>,
+    server_access: Option<Arc<AccessList>>,
+    upstream_tls: HashMap<String, Arc<UpstreamTls>>,
+    // Страницы для ответа, когда открыты circuit breaker'ы всех бэкендов upstream'а
+    breaker_pages: HashMap<String, Bytes>,
+    // Страницы блокировки сервера и upstream'ов со своими настройками
+    block_page: Arc<BlockPage>,
+    upstream_block_pages: HashMap<String, Arc<BlockPage>>,
+    client_ip_resolver: Arc<ClientIpResolver>,
+    routes: Arc<Vec<Route>>,
+    host_router: Arc<HostRouter>,
     config: Config,
     server_name: String,
 }
 
 impl MyProxy {
-    #[instrument(name = "MyProxy::new_for_server")]
-    pub fn new_for_server(config: Config, server_name: &str) -> Self {
+    #[instrument(name = "MyProxy::new_for_server", skip(balancers, rate_limiters, server_limiters, access_lists))]
+    pub fn new_for_server(
+        config: Config,
+        server_name: &str,
+        balancers: &HashMap<String, Arc<LoadBalancer>>,
+        rate_limiters: &HashMap<String, Arc<RateLimiter>>,
+        server_limiters: &HashMap<String, Arc<RateLimiter>>,
+        access_lists: &HashMap<String, Arc<AccessList>>,
+    ) -> Result<Self, String> {
         let server = config.get_server(server_name)
-            .unwrap_or_else(|| panic!("Server '{}' not found in config", server_name));
+            .ok_or_else(|| format!("Server '{}' not found in config", server_name))?;
 
         let mut waf_engines = HashMap::new();
+        let mut server_balancers = HashMap::new();
+        let mut server_rate_limiters = HashMap::new();
+        let mut server_access_lists = HashMap::new();
+        let mut upstream_tls = HashMap::new();
+        let mut breaker_pages = HashMap::new();
+        let mut upstream_block_pages = HashMap::new();
 
         info!("Loading WAF rules for each upstream");
 
         for upstream_key in &server.upstreams {
             let upstream = config.get_upstream(upstream_key)
-                .unwrap_or_else(|| panic!("Upstream '{}' not found in config", upstream_key));
+                .ok_or_else(|| format!("Upstream '{}' of server '{}' not found in config", upstream_key, server_name))?;
+
+            let balancer = balancers.get(upstream_key)
+                .cloned()
+                .unwrap_or_else(|| Arc::new(LoadBalancer::new(upstream)));
+            server_balancers.insert(upstream_key.clone(), balancer);
+
+            let rate_limiter = rate_limiters.get(upstream_key)
+                .cloned()
+                .unwrap_or_else(|| Arc::new(RateLimiter::new(&upstream.rate_limits)));
+            server_rate_limiters.insert(upstream_key.clone(), rate_limiter);
+
+            if let Some(access_list) = access_lists.get(upstream_key) {
+                server_access_lists.insert(upstream_key.clone(), access_list.clone());
+            }
+
+            if let Some(tls) = &upstream.tls {
+                let tls = UpstreamTls::load(tls, &config)
+                    .map_err(|e| format!("TLS settings of upstream '{}': {}", upstream_key, e))?;
+                upstream_tls.insert(upstream_key.clone(), Arc::new(tls));
+            }
+
+            if let Some(page) = upstream.circuit_breaker.as_ref().and_then(|b| b.page_file.as_ref()) {
+                let path = config.resolve_path(page);
+                let content = std::fs::read(&path)
+                    .map_err(|e| format!("circuit_breaker.page_file of upstream '{}': cannot read '{}': {}", upstream_key, path.display(), e))?;
+                breaker_pages.insert(upstream_key.clone(), Bytes::from(content));
+            }
+
+            if let Some(page) = &upstream.block_page {
+                let page = BlockPage::new(page, &config)
+                    .map_err(|e| format!("block_page of upstream '{}': {}", upstream_key, e))?;
+                upstream_block_pages.insert(upstream_key.clone(), Arc::new(page));
+            }
             
-            let rules_path = format!(
-                "{}/rules/{}/crs-setup.conf", 
-                env!("CARGO_MANIFEST_DIR"),
-                upstream.waf_rules
-            );
+            let rules_path = config.rules_path(&upstream.waf_rules).join("crs-setup.conf");
             
             match Engine::load(&rules_path) {
                 Ok(engine) => {
-                    let shared_waf = Arc::new(SharedWaf::new(engine, rules_path.clone()));
+                    let shared_waf = Arc::new(SharedWaf::new(engine, rules_path));
                     waf_engines.insert(upstream_key.clone(), shared_waf);
                     info!(upstream = %upstream_key, rules = %upstream.waf_rules, "WAF rules loaded successfully");
                 }
                 Err(e) => {
                     error!(upstream = %upstream_key, rules = %upstream.waf_rules, error = %e, "Failed to load WAF rules");
-                    let default_path = format!("{}/rules/default/default.conf", env!("CARGO_MANIFEST_DIR"));
+                    let default_path = config.rules_path("default").join("default.conf");
                     match Engine::load(&default_path) {
                         Ok(engine) => {
                             let shared_waf = Arc::new(SharedWaf::new(engine, default_path));
@@ -103,7 +227,7 @@ impl MyProxy {
                                 }
                                 Err(e) => {
                                     error!(error = %e, "Failed to create empty engine");
-                                    panic!("Cannot continue without WAF engine");
+                                    return Err(format!("Cannot create WAF engine for upstream '{}': {}", upstream_key, e));
                                 }
                             }
                         }
@@ -112,28 +236,170 @@ impl MyProxy {
             }
         }
 
-        Self { 
+        let server_rate_limiter = server_limiters.get(server_name)
+            .cloned()
+            .unwrap_or_else(|| Arc::new(RateLimiter::new(&server.rate_limits)));
+        let server_access = match &server.access {
+            Some(access) => Some(Arc::new(
+                AccessList::new(access, &config).map_err(|e| format!("Access list of server '{}': {}", server_name, e))?,
+            )),
+            None => None,
+        };
+
+        let block_page = match &server.block_page {
+            Some(page) => BlockPage::new(page, &config)
+                .map_err(|e| format!("block_page of server '{}': {}", server_name, e))?,
+            None => BlockPage::default(),
+        };
+
+        let routes = server.routes.iter().enumerate()
+            .map(|(i, route)| Route::new(route).map_err(|e| format!("routes[{}] of server '{}': {}", i, server_name, e)))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let host_router = HostRouter::new(server, &config)
+            .map_err(|e| format!("server_names of server '{}': {}", server_name, e))?;
+
+        let client_ip_resolver = Arc::new(
+            ClientIpResolver::new(server).map_err(|e| format!("trusted_proxies of server '{}': {}", server_name, e))?,
+        );
+
+        Ok(Self { 
             waf_engines, 
+            balancers: server_balancers,
+            rate_limiters: server_rate_limiters,
+            server_rate_limiter,
+            access_lists: server_access_lists,
+            server_access,
+            upstream_tls,
+            breaker_pages,
+            block_page: Arc::new(block_page),
+            upstream_block_pages,
+            client_ip_resolver,
+            routes: Arc::new(routes),
+            host_router: Arc::new(host_router),
             config,
             server_name: server_name.to_string(),
+        })
+    }
+
+    pub fn server_access(&self) -> Option<&Arc<AccessList>> {
+        self.server_access.as_ref()
+    }
+
+    // Страница блокировки upstream'а, если задана, иначе сервера
+    fn block_page(&self, upstream: Option<&str>) -> &BlockPage {
+        upstream
+            .and_then(|name| self.upstream_block_pages.get(name))
+            .unwrap_or(&self.block_page)
+    }
+
+    // Ответ на запрос, заблокированный ModSecurity: редирект из правила
+    // или страница блокировки со статусом из intervention
+    async fn respond_waf_block(
+        &self,
+        session: &mut Session,
+        upstream: &str,
+        request_id: &str,
+        waf_result: &WafCheckResult,
+    ) -> Result<()> {
+        if let Some((status, location)) = redirect_location(waf_result) {
+            let mut response = ResponseHeader::build(status, Some(2))?;
+            response.insert_header("Location", location)?;
+            response.insert_header("Content-Length", "0")?;
+            return session.write_response_header(Box::new(response), true).await;
         }
+
+        let page = self.block_page(Some(upstream));
+        let info = BlockInfo { request_id, rule_id: Some(waf_result.rule_id) };
+        page.respond(session, page.waf_status(waf_result.status), &info, &[]).await
     }
 
-    fn get_upstream_key_and_config_for_host(&self, host: &str) -> Option<(&String, &UpstreamConfig)> {
-        let server = self.config.get_server(&self.server_name)?;
-        
-        for upstream_key in &server.upstreams {
-            if let Some(upstream) = self.config.get_upstream(upstream_key) {
-                if host.to_lowercase() == upstream.sni.to_lowercase() {
-                    return Some((upstream_key, upstream));
-                }
-                if host.contains(&upstream.sni.to_lowercase()) {
-                    return Some((upstream_key, upstream));
-                }
-            }
+    // Тело запроса к upstream'у с включённым WAF; удерживается только в режиме block
+    fn new_request_body(&self, upstream: &UpstreamConfig, mode: WafMode) -> RequestBody {
+        let body = &upstream.request_body;
+        let max_size = body.max_size
+            .unwrap_or_else(|| self.config.get_server_max_body_size(&self.server_name));
+        let spill_dir = body.spill_dir.as_ref()
+            .map_or_else(std::env::temp_dir, |dir| self.config.resolve_path(dir));
+        // На байт больше лимита audit log, чтобы он отметил обрезку
+        let sample_limit = audit_log().map_or(0, |audit| audit.config().max_body_bytes + 1);
+        RequestBody::new(body.mode, mode == WafMode::Block, max_size, body.spill_threshold, spill_dir, sample_limit)
+    }
+
+    // Политика повтора upstream'а, если этот запрос ещё можно повторить
+    fn retry_policy(&self, session: &Session, context: &RequestContext) -> Option<&RetryConfig> {
+        let retry = context.upstream_name.as_ref()
+            .and_then(|name| self.config.get_upstream(name))
+            .and_then(|upstream| upstream.retry.as_ref())?;
+        let method = session.req_header().method.as_str();
+        let allowed = context.retries < retry.max_retries
+            && IDEMPOTENT_METHODS.contains(&method)
+            && !session.as_ref().retry_buffer_truncated();
+        allowed.then_some(retry)
+    }
+
+    // Запись audit log по нарушениям, накопленным за запрос
+    fn build_audit_record(&self, session: &Session, context: &RequestContext, audit: &AuditLogger) -> AuditRecord {
+        let req = session.req_header();
+
+        let mut headers: BTreeMap<String, String> = BTreeMap::new();
+        for (name, value) in req.headers.iter() {
+            let value = String::from_utf8_lossy(value.as_bytes());
+            headers
+                .entry(name.as_str().to_string())
+                .and_modify(|v| {
+                    v.push_str(", ");
+                    v.push_str(&value);
+                })
+                .or_insert_with(|| value.to_string());
         }
-        
-        None
+
+        let (body, body_truncated) = audit.truncate_body(context.request_body.sample());
+        let blocked = context.violations.iter().any(|v| v.blocked);
+
+        AuditRecord {
+            transaction: AuditTransaction {
+                time_stamp: Utc::now().to_rfc3339(),
+                request_id: context.request_id.clone(),
+                client_ip: context.client_ip.clone(),
+                server: self.server_name.clone(),
+                upstream: context.upstream_name.clone(),
+                request: AuditRequest {
+                    method: req.method.to_string(),
+                    uri: req.uri.to_string(),
+                    http_version: format!("{:?}", req.version),
+                    headers,
+                    body,
+                    body_truncated,
+                },
+                response: AuditResponse {
+                    http_code: session.response_written().map(|r| r.status.as_u16()).unwrap_or(0),
+                },
+                action: if blocked { "blocked" } else { "detected" }.to_string(),
+                messages: context
+                    .violations
+                    .iter()
+                    .map(|v| AuditMessage {
+                        source: v.source.clone(),
+                        blocked: v.blocked,
+                        reason: v.reason.clone(),
+                        rule_id: v.rule_id,
+                        rules: v.rules.clone(),
+                    })
+                    .collect(),
+            },
+        }
+    }
+
+    // Upstream для запроса: сначала правила routes по порядку, затем выбор по Host
+    // path - после normalize_path
+    fn route(&self, req: &RequestHeader, path: &str) -> Option<(&String, &UpstreamConfig)> {
+        let host = request_host(req);
+        let upstream = match self.routes.iter().find(|route| route.matches(&host, path, req)) {
+            Some(route) => route.upstream.as_str(),
+            None => self.host_router.select(&host)?,
+        };
+        self.config.upstreams.get_key_value(upstream)
     }
 
     // Остальные методы остаются прежними, но используем новую структуру
@@ -161,23 +427,6 @@ impl MyProxy {
         info
     }
 
-    pub async fn watch_all_sighup(&self) {
-        let mut handles = vec![];
-        for (name, waf) in &self.waf_engines {
-            let waf_clone = waf.clone();
-            let name_clone = name.clone();
-            let handle = tokio::spawn(async move {
-                info!("Watching SIGHUP for {}", name_clone);
-                waf_clone.watch_sighup().await;
-            });
-            handles.push(handle);
-        }
-        
-        for handle in handles {
-            let _ = handle.await;
-        }
-    }
-
     pub fn reload_all_rules(&self) -> Result<(), String> {
         let mut errors = Vec::new();
         
@@ -217,6 +466,18 @@ impl Clone for MyProxy {
         
         Self {
             waf_engines,
+            balancers: self.balancers.clone(),
+            rate_limiters: self.rate_limiters.clone(),
+            server_rate_limiter: self.server_rate_limiter.clone(),
+            access_lists: self.access_lists.clone(),
+            server_access: self.server_access.clone(),
+            upstream_tls: self.upstream_tls.clone(),
+            breaker_pages: self.breaker_pages.clone(),
+            block_page: self.block_page.clone(),
+            upstream_block_pages: self.upstream_block_pages.clone(),
+            client_ip_resolver: self.client_ip_resolver.clone(),
+            routes: self.routes.clone(),
+            host_router: self.host_router.clone(),
             config: self.config.clone(),
             server_name: self.server_name.clone(),
         }
@@ -236,37 +497,74 @@ impl ProxyHttp for MyProxy {
         session: &mut Session,
         ctx: &mut Self::CTX,
     ) -> pingora::Result<Box<HttpPeer>> {
-        let host_header = session
-            .req_header()
-            .headers
-            .get("host")
-            .and_then(|h| h.to_str().ok())
-            .unwrap_or("")
-            .to_lowercase();
+        // Upstream уже выбран в request_filter
+        let routed = ctx.as_ref()
+            .and_then(|c| c.upstream_name.as_ref())
+            .and_then(|name| self.config.upstreams.get_key_value(name));
+        let fallback = || {
+            let req = session.req_header();
+            normalize_path(req.uri.path()).ok().and_then(|path| self.route(req, &path))
+        };
+        let (upstream_key, upstream) = match routed.or_else(fallback) {
+            Some(found) => found,
+            None => {
+                return Err(Error::explain(
+                    ErrorType::HTTPStatus(502),
+                    format!("No upstream configured for host '{}'", request_host(session.req_header())),
+                ));
+            }
+        };
 
-        // В upstream_peer использовать новый метод:
-        let (upstream_key, upstream) = self.get_upstream_key_and_config_for_host(&host_header)
-            .expect("No upstream configured for this host");
+        let client_ip = ctx.as_ref().map(|c| c.client_ip.clone()).unwrap_or_default();
+        let request_id = ctx.as_ref().map(|c| c.request_id.clone()).unwrap_or_default();
+        let tried = ctx.as_ref().map(|c| c.tried_backends.as_slice()).unwrap_or_default();
 
-        if let Some(ctx) = ctx {
-            ctx.upstream_name = Some(upstream_key.clone());
-        }
+        let backend = match self.balancers.get(upstream_key)
+            .and_then(|balancer| balancer.select(session.req_header(), &client_ip, tried))
+        {
+            Some(backend) => backend,
+            None => {
+                warn!(request_id = %request_id, upstream = %upstream_key, "No available backends for upstream");
+                return Err(Error::explain(
+                    ErrorType::HTTPStatus(502),
+                    format!("No available backends for upstream '{}'", upstream_key),
+                ));
+            }
+        };
 
         debug!(
+            request_id = %request_id,
             server = %self.server_name,
             upstream = %upstream_key,
+            backend = %backend.addr,
             waf_rules = %upstream.waf_rules,
             "Routing request"
         );
 
-        let backend_addr = upstream.addrs.first()
-            .expect("No backend addresses configured");
-            
-        let peer = HttpPeer::new(
-            backend_addr.clone(),
+        let mut peer = HttpPeer::new(
+            backend.addr.clone(),
             upstream.use_tls,
             upstream.sni.clone(),
         );
+        if let Some(tls) = self.upstream_tls.get(upstream_key).filter(|_| upstream.use_tls) {
+            tls.apply(&mut peer);
+        }
+        let timeouts = &upstream.timeouts;
+        peer.options.connection_timeout = Some(Duration::from_millis(timeouts.connect_ms));
+        peer.options.total_connection_timeout = timeouts.total_connect_ms.map(Duration::from_millis);
+        peer.options.read_timeout = Some(Duration::from_millis(timeouts.read_ms));
+        peer.options.write_timeout = Some(Duration::from_millis(timeouts.write_ms));
+        peer.options.idle_timeout = timeouts.idle_ms.map(Duration::from_millis);
+
+        if let Some(ctx) = ctx {
+            ctx.upstream_name = Some(upstream_key.clone());
+            ctx.tried_backends.push(backend.addr.clone());
+            backend.breaker.acquire();
+            // Заменяем предыдущий guard (при повторной попытке) новым
+            ctx.backend = Some(BackendGuard::new(backend));
+            ctx.breaker_recorded = false;
+            ctx.upstream_started = Some(Instant::now());
+        }
         
         Ok(Box::new(peer))
     }
@@ -283,33 +581,70 @@ impl ProxyHttp for MyProxy {
     async fn request_filter(&self, session: &mut Session, ctx: &mut Self::CTX) -> pingora::Result<bool> {
         let request_headers = session.req_header();
         
-        let client_ip = session
-            .client_addr()
-            .map(|addr| addr.to_string())
-            .unwrap_or_else(|| "unknown".to_string());
+        // Реальный адрес клиента с учётом доверенных прокси
+        let client = self.client_ip_resolver.resolve(session);
+        let client_addr = client.map(|addr| addr.ip());
+        let ip = client_addr.map(|addr| addr.to_string()).unwrap_or_default();
         
         // Создаем новый контекст если его нет
         if ctx.is_none() {
-            *ctx = Some(RequestContext::new(&client_ip));
+            *ctx = Some(RequestContext::new(&ip));
         }
         
         let context = ctx.as_mut().unwrap();
         
         // Очищаем для нового запроса
-        context.body_inspector.clear();
+        context.request_body = RequestBody::disabled();
         context.violations.clear();
-        
-        let host_header = request_headers
+
+        // Идентификатор от доверенного прокси сохраняем, иначе выдаём свой
+        let trusted_peer = self.client_ip_resolver.is_trusted_peer(session);
+        context.request_id = request_headers
             .headers
-            .get("host")
-            .and_then(|h| h.to_str().ok())
-            .unwrap_or("unknown")
-            .to_lowercase();
+            .get(REQUEST_ID_HEADER)
+            .and_then(|v| v.to_str().ok())
+            .filter(|id| trusted_peer && request_id::is_valid(id))
+            .map(|id| id.to_string())
+            .unwrap_or_else(request_id::generate);
 
-        let (upstream_key, _upstream) = match self.get_upstream_key_and_config_for_host(&host_header) {
+        // Списки доступа сервера проверяются первыми; allow снимает deny, бан и лимиты
+        let server_access = self.server_access.as_ref()
+            .map_or(AccessDecision::Unlisted, |list| list.check(client_addr));
+        if server_access == AccessDecision::Deny {
+            debug!(request_id = %context.request_id, client_ip = %ip, "Client denied by server access list");
+            metrics::inc_access_denied(&self.server_name);
+            let info = BlockInfo { request_id: &context.request_id, rule_id: None };
+            self.block_page(None).respond(session, 403, &info, &[]).await?;
+            return Ok(true);
+        }
+        let mut allowed = server_access == AccessDecision::Allow;
+        let mut bypass_waf = allowed && self.server_access.as_ref().is_some_and(|list| list.bypass_waf);
+
+        // Забаненные клиенты отсекаются до маршрутизации и WAF
+        if let Some(remaining) = ban_list().banned_for(&ip).filter(|_| !allowed) {
+            debug!(request_id = %context.request_id, client_ip = %ip, remaining_secs = remaining.as_secs(), "Request from banned client");
+            metrics::inc_banned_request(&self.server_name);
+
+            let info = BlockInfo { request_id: &context.request_id, rule_id: None };
+            let retry_after = remaining.as_secs().max(1).to_string();
+            self.block_page(None).respond(session, 403, &info, &[("Retry-After", retry_after)]).await?;
+            return Ok(true);
+        }
+        
+        // Маршрут выбирается по пути, который увидит бэкенд: иначе "/api/../admin"
+        // попал бы под маршрут и правила /api/, а бэкенд отдал бы /admin
+        let path = match normalize_path(request_headers.uri.path()) {
+            Ok(path) => path,
+            Err(e) => {
+                warn!(request_id = %context.request_id, client_ip = %ip, error = %e, "Rejected request path");
+                session.respond_error(400).await?;
+                return Ok(true);
+            }
+        };
+        let (upstream_key, upstream) = match self.route(request_headers, &path) {
             Some((key, upstream)) => (key, upstream),
             None => {
-                warn!(host = %host_header, "Unknown upstream for host");
+                warn!(request_id = %context.request_id, host = %request_host(request_headers), "Unknown upstream for host");
                 session.respond_error(404).await?;
                 return Ok(true);
             }
@@ -317,11 +652,94 @@ impl ProxyHttp for MyProxy {
 
         // Сохраняем ключ
         context.upstream_name = Some(upstream_key.clone());
+        context.mode = upstream.mode;
+
+        if let Some(list) = self.access_lists.get(upstream_key).filter(|_| !allowed) {
+            match list.check(client_addr) {
+                AccessDecision::Deny => {
+                    debug!(request_id = %context.request_id, upstream = %upstream_key, client_ip = %ip, "Client denied by upstream access list");
+                    metrics::inc_access_denied(&self.server_name);
+                    let info = BlockInfo { request_id: &context.request_id, rule_id: None };
+                    self.block_page(Some(upstream_key)).respond(session, 403, &info, &[]).await?;
+                    return Ok(true);
+                }
+                AccessDecision::Allow => {
+                    allowed = true;
+                    bypass_waf = list.bypass_waf;
+                }
+                AccessDecision::Unlisted => {}
+            }
+        }
+
+        // Все бэкенды отсечены circuit breaker'ами: отвечаем сразу, не нагружая WAF
+        if let Some(retry_after) = self.balancers.get(upstream_key).and_then(|b| b.breakers_open()) {
+            debug!(request_id = %context.request_id, upstream = %upstream_key, "All backends have open circuit breakers");
+            metrics::inc_circuit_open(upstream_key);
+
+            let status = upstream.circuit_breaker.as_ref().map_or(503, |b| b.status);
+            let page = self.breaker_pages.get(upstream_key).cloned();
+            let mut response = ResponseHeader::build(status, Some(3))?;
+            response.insert_header("Retry-After", retry_after.as_secs().max(1).to_string())?;
+            response.insert_header("Content-Length", page.as_ref().map_or(0, |p| p.len()).to_string())?;
+            if page.is_some() {
+                response.insert_header("Content-Type", "text/html; charset=utf-8")?;
+            }
+            session.write_response_header(Box::new(response), page.is_none()).await?;
+            if let Some(page) = page {
+                session.write_response_body(Some(page), true).await?;
+            }
+            return Ok(true);
+        }
+
+        if bypass_waf {
+            debug!(request_id = %context.request_id, upstream = %upstream_key, client_ip = %ip, "Allowlisted client, WAF bypassed");
+            return Ok(false);
+        }
+
+        // Лимиты проверяются до WAF и независимо от его режима
+        let limited = if allowed {
+            None
+        } else {
+            self.server_rate_limiter.check(request_headers, &path, &ip, upstream_key)
+                .or_else(|| {
+                    self.rate_limiters.get(upstream_key)
+                        .and_then(|limiter| limiter.check(request_headers, &path, &ip, upstream_key))
+                })
+        };
+        if let Some(limited) = limited {
+            warn!(
+                request_id = %context.request_id,
+                upstream = %upstream_key,
+                client_ip = %ip,
+                policy = %limited.policy,
+                retry_after = limited.retry_after,
+                "Rate limit exceeded"
+            );
+
+            context.violations.push(WafViolation {
+                rule_id: 429,
+                reason: format!("Rate limit exceeded: {}", limited.policy),
+                blocked: true,
+                timestamp: Utc::now(),
+                source: "rate_limit".to_string(),
+                rules: Vec::new(),
+            });
+
+            let info = BlockInfo { request_id: &context.request_id, rule_id: None };
+            let retry_after = limited.retry_after.to_string();
+            self.block_page(Some(upstream_key)).respond(session, 429, &info, &[("Retry-After", retry_after)]).await?;
+            return Ok(true);
+        }
+
+        if context.mode == WafMode::Off {
+            debug!(request_id = %context.request_id, upstream = %upstream_key, "WAF disabled for upstream");
+            return Ok(false);
+        }
 
         let waf = match self.waf_engines.get(upstream_key) {
             Some(waf) => waf,
             None => {
-                error!(upstream = %upstream_key, "No WAF configured for upstream");
+                error!(request_id = %context.request_id, upstream = %upstream_key, "No WAF configured for upstream");
                 session.respond_error(500).await?;
                 return Ok(true);
             }
@@ -330,44 +748,79 @@ impl ProxyHttp for MyProxy {
         let method = request_headers.method.as_str();
         let uri = request_headers.uri.to_string();
 
-        let mut headers_map = HMap::new();
-        for (name, value) in request_headers.headers.iter() {
-            headers_map.insert(name.clone(), value.clone());
+        let mut waf_tx = match waf.transaction(&context.request_id) {
+            Ok(tx) => tx,
+            Err(e) => {
+                error!(request_id = %context.request_id, upstream = %upstream_key, error = %e, "Failed to create WAF transaction");
+                return Ok(false);
+            }
+        };
+
+        if let Some(client) = &client {
+            let server_addr = session.server_addr().and_then(|addr| addr.as_inet());
+            if let Err(e) = waf_tx.process_connection(client, server_addr) {
+                warn!(request_id = %context.request_id, upstream = %upstream_key, error = %e, "Failed to pass connection to WAF");
+            }
         }
 
-        let waf_result = waf.check_detailed(&headers_map, &uri, &method, None);
+        let started = Instant::now();
+        let waf_result = waf_tx.process_request_headers(&request_headers.headers, &uri, method);
+        metrics::observe_waf(upstream_key, "request_headers", started);
+        context.waf_tx = Some(waf_tx);
+        context.request_body = self.new_request_body(upstream, context.mode);
 
         debug!(
+            request_id = %context.request_id,
             upstream = %upstream_key,
             method = %method,
             uri = %uri,
-            client_ip = %client_ip,
+            client_ip = %ip,
             "WAF header check"
         );
 
         if !waf_result.allowed {
-            warn!(
-                upstream = %upstream_key,
-                method = %method,
-                uri = %uri,
-                rule_id = %waf_result.rule_id,
-                reason = %waf_result.reason,
-                "WAF blocked request (headers/URI)"
-            );
+            let blocked = context.mode == WafMode::Block;
+            if blocked {
+                warn!(
+                    request_id = %context.request_id,
+                    upstream = %upstream_key,
+                    method = %method,
+                    uri = %uri,
+                    rule_id = %waf_result.rule_id,
+                    severity = ?waf_result.matched_rule.as_ref().and_then(|r| r.severity_name()),
+                    reason = %waf_result.reason,
+                    "WAF blocked request (headers/URI)"
+                );
+            } else {
+                warn!(
+                    request_id = %context.request_id,
+                    upstream = %upstream_key,
+                    method = %method,
+                    uri = %uri,
+                    rule_id = %waf_result.rule_id,
+                    severity = ?waf_result.matched_rule.as_ref().and_then(|r| r.severity_name()),
+                    reason = %waf_result.reason,
+                    "WAF detected violation in request (headers/URI, detect mode)"
+                );
+            }
             
             context.violations.push(WafViolation {
                 rule_id: waf_result.rule_id,
                 reason: waf_result.reason.clone(),
-                blocked: true,
+                blocked,
                 timestamp: Utc::now(),
                 source: "header".to_string(),
+                rules: waf_result.rules.clone(),
             });
             
-            session.respond_error(403).await?;
-            return Ok(true);
+            if blocked {
+                self.respond_waf_block(session, upstream_key, &context.request_id, &waf_result).await?;
+                return Ok(true);
+            }
         }
 
         debug!(
+            request_id = %context.request_id,
             upstream = %upstream_key,
             method = %method,
             uri = %uri,
@@ -395,184 +848,624 @@ impl ProxyHttp for MyProxy {
         let upstream_name = match &context.upstream_name {
             Some(name) => name,
             None => {
-                warn!("No upstream determined for body inspection");
+                warn!(request_id = %context.request_id, "No upstream determined for body inspection");
                 return Ok(());
             }
         };
 
-        let waf = match self.waf_engines.get(upstream_name) {
-            Some(waf) => waf,
-            None => {
-                error!(upstream = %upstream_name, "No WAF configured for body inspection");
-                return Ok(());
-            }
-        };
+        if context.waf_tx.is_none() || context.request_body_checked {
+            return Ok(());
+        }
 
-        if let Some(chunk) = body {
-            if let Err(e) = context.body_inspector.append_chunk(chunk) {
+        // Фрагменты передаются в ModSecurity по мере получения
+        let mut waf_result = None;
+        let chunk = body.clone().filter(|_| context.request_body.inspecting);
+        if let Some(chunk) = &chunk {
+            if let Err(e) = context.request_body.record(chunk) {
+                let blocked = context.mode == WafMode::Block;
                 error!(
+                    request_id = %context.request_id,
                     upstream = %upstream_name,
                     client_ip = %context.client_ip,
+                    blocked = blocked,
                     "Body size limit exceeded: {}", e
                 );
                 
                 context.violations.push(WafViolation {
                     rule_id: 413,
                     reason: format!("Body size limit exceeded: {}", e),
-                    blocked: true,
+                    blocked,
                     timestamp: Utc::now(),
                     source: "body".to_string(),
+                    rules: Vec::new(),
                 });
                 
-                session.respond_error(413).await?;
-                return Err(pingora::Error::new_str("Body size limit exceeded"));
+                if blocked {
+                    metrics::inc_body_too_large(upstream_name);
+                    let info = BlockInfo { request_id: &context.request_id, rule_id: None };
+                    self.block_page(Some(upstream_name)).respond(session, 413, &info, &[]).await?;
+                    return Err(pingora::Error::new_str("Body size limit exceeded"));
+                }
+
+                // В режиме detect остаток тела уходит на бэкенд без проверки
+                context.request_body.inspecting = false;
+            } else if let Some(waf_tx) = context.waf_tx.as_mut() {
+                match waf_tx.append_request_body(chunk) {
+                    Some(result) if !result.allowed => waf_result = Some(result),
+                    Some(result) => {
+                        warn!(request_id = %context.request_id, upstream = %upstream_name, reason = %result.reason, "Failed to pass request body to WAF");
+                    }
+                    None => {}
+                }
             }
         }
 
-        if end_of_stream {
-            let full_body = context.body_inspector.get_body();
+        // До решения WAF фрагменты не уходят на бэкенд
+        if let Some(chunk) = chunk.filter(|_| waf_result.is_none() && context.request_body.is_holding()) {
+            if let Err(e) = context.request_body.hold(&chunk).await {
+                error!(request_id = %context.request_id, upstream = %upstream_name, error = %e, "Failed to hold request body");
+                return Err(Error::because(ErrorType::InternalError, "Failed to hold request body", e));
+            }
+            *body = Some(Bytes::new());
+        }
+
+        if waf_result.is_none() && end_of_stream {
+            // Фаза 2 выполняется и для пустого тела: в ней CRS подводит итог anomaly score
+            let started = Instant::now();
+            waf_result = context.waf_tx.as_mut().map(|waf_tx| waf_tx.process_request_body());
+            metrics::observe_waf(upstream_name, "request_body", started);
+        }
+        let Some(waf_result) = waf_result else {
+            return Ok(());
+        };
+        context.request_body_checked = true;
+
+        let request_headers = session.req_header();
+        let method = request_headers.method.as_str();
+        let uri = request_headers.uri.to_string();
+        let body_size = context.request_body.size;
+
+        debug!(
+            request_id = %context.request_id,
+            upstream = %upstream_name,
+            method = %method,
+            uri = %uri,
+            client_ip = %context.client_ip,
+            body_size = body_size,
+            held = context.request_body.held_size(),
+            "WAF body check"
+        );
+
+        if !waf_result.allowed && context.mode == WafMode::Detect {
+            warn!(
+                request_id = %context.request_id,
+                upstream = %upstream_name,
+                method = %method,
+                uri = %uri,
+                client_ip = %context.client_ip,
+                body_size = body_size,
+                rule_id = %waf_result.rule_id,
+                severity = ?waf_result.matched_rule.as_ref().and_then(|r| r.severity_name()),
+                reason = %waf_result.reason,
+                "WAF detected violation in request body (detect mode)"
+            );
+
+            context.violations.push(WafViolation {
+                rule_id: waf_result.rule_id,
+                reason: waf_result.reason.clone(),
+                blocked: false,
+                timestamp: Utc::now(),
+                source: "body".to_string(),
+                rules: waf_result.rules.clone(),
+            });
+        } else if !waf_result.allowed {
+            warn!(
+                request_id = %context.request_id,
+                upstream = %upstream_name,
+                method = %method,
+                uri = %uri,
+                client_ip = %context.client_ip,
+                body_size = body_size,
+                rule_id = %waf_result.rule_id,
+                severity = ?waf_result.matched_rule.as_ref().and_then(|r| r.severity_name()),
+                reason = %waf_result.reason,
+                "WAF blocked request body"
+            );
             
-            if !full_body.is_empty() {
-                let request_headers = session.req_header();
-                let method = request_headers.method.as_str();
-                let uri = request_headers.uri.to_string();
-
-                let mut headers_map = HMap::new();
-                for (name, value) in request_headers.headers.iter() {
-                    headers_map.insert(name.clone(), value.clone());
+            context.violations.push(WafViolation {
+                rule_id: waf_result.rule_id,
+                reason: waf_result.reason.clone(),
+                blocked: true,
+                timestamp: Utc::now(),
+                source: "body".to_string(),
+                rules: waf_result.rules.clone(),
+            });
+
+            // отправляем редирект или страницу блокировки; удержанное тело
+            // на бэкенд не уходит, а начатый запрос к нему обрывается
+            self.respond_waf_block(session, upstream_name, &context.request_id, &waf_result).await?;
+            return Err(pingora::Error::new_str("WAF violation in request body"));
+        } else {
+            debug!(
+                request_id = %context.request_id,
+                upstream = %upstream_name,
+                method = %method,
+                uri = %uri,
+                body_size = body_size,
+                "Request body passed WAF check"
+            );
+        }
+
+        // Проверенное тело уходит на бэкенд целиком
+        if context.request_body.is_holding() {
+            match context.request_body.release().await {
+                Ok(held) if !held.is_empty() => *body = Some(held),
+                Ok(_) => {}
+                Err(e) => {
+                    error!(request_id = %context.request_id, upstream = %upstream_name, error = %e, "Failed to read held request body");
+                    return Err(Error::because(ErrorType::InternalError, "Failed to read held request body", e));
                 }
+            }
+        }
 
-                let waf_result = waf.check_detailed(
-                    &headers_map, 
-                    &uri, 
-                    &method, 
-                    Some(&full_body)
-                );
+        Ok(())
+    }
 
-                debug!(
-                    upstream = %upstream_name,
-                    method = %method,
-                    uri = %uri,
-                    client_ip = %context.client_ip,
-                    body_size = full_body.len(),
-                    "WAF body check"
-                );
+    // Заголовки для бэкенда: адрес клиента, исходный протокол и идентификатор запроса
+    async fn upstream_request_filter(
+        &self,
+        session: &mut Session,
+        upstream_request: &mut RequestHeader,
+        ctx: &mut Self::CTX,
+    ) -> Result<()> {
+        let Some(context) = ctx.as_ref() else {
+            return Ok(());
+        };
+        let trusted_peer = self.client_ip_resolver.is_trusted_peer(session);
 
-                if !waf_result.allowed {
-                    warn!(
-                        upstream = %upstream_name,
-                        method = %method,
-                        uri = %uri,
-                        client_ip = %context.client_ip,
-                        body_size = full_body.len(),
-                        rule_id = %waf_result.rule_id,
-                        reason = %waf_result.reason,
-                        "WAF blocked request body"
-                    );
-                    
-                    context.violations.push(WafViolation {
-                        rule_id: waf_result.rule_id,
-                        reason: waf_result.reason.clone(),
-                        blocked: true,
-                        timestamp: Utc::now(),
-                        source: "body".to_string(),
-                    });
-                    
-                    *body = None;
-
-                    // помечаем downstream как завершённый
-                    session.as_downstream();
-
-                    // отправляем статус
-                    session.respond_error(403).await?;
-                    return Ok(());
-                    //return Err(pingora::Error::new_str("WAF violation in request body"));
-                }
+        // Цепочку от доверенного прокси продолжаем его адресом, чужую начинаем заново
+        let existing: Vec<&str> = session
+            .req_header()
+            .headers
+            .get_all("x-forwarded-for")
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .collect();
+        let forwarded_for = match self.client_ip_resolver.peer(session) {
+            Some(peer) if trusted_peer && !existing.is_empty() => format!("{}, {}", existing.join(", "), peer.ip()),
+            _ => context.client_ip.clone(),
+        };
+        upstream_request.insert_header("X-Forwarded-For", forwarded_for)?;
 
-                debug!(
-                    upstream = %upstream_name,
-                    method = %method,
-                    uri = %uri,
-                    body_size = full_body.len(),
-                    "Request body passed WAF check"
-                );
+        let proto = session
+            .req_header()
+            .headers
+            .get("x-forwarded-proto")
+            .and_then(|v| v.to_str().ok())
+            .filter(|_| trusted_peer)
+            .map(|v| v.to_string())
+            .unwrap_or_else(|| {
+                let tls = session.digest().is_some_and(|d| d.ssl_digest.is_some());
+                if tls { "https" } else { "http" }.to_string()
+            });
+        upstream_request.insert_header("X-Forwarded-Proto", proto)?;
+        upstream_request.insert_header("X-Request-Id", context.request_id.as_str())?;
+
+        Ok(())
+    }
+
+    fn upstream_response_filter(
+        &self,
+        session: &mut Session,
+        upstream_response: &mut ResponseHeader,
+        ctx: &mut Self::CTX,
+    ) -> Result<()> {
+        let Some(context) = ctx.as_mut() else {
+            return Ok(());
+        };
+
+        if let Some(upstream_name) = &context.upstream_name {
+            metrics::observe_upstream(upstream_name, upstream_response.status.as_u16(), context.upstream_started);
+        }
+
+        record_breaker(context, !upstream_response.status.is_server_error());
+        if let Some(guard) = context.backend.as_ref() {
+            if upstream_response.status.is_server_error() {
+                if guard.backend.health.report_failure() {
+                    warn!(request_id = %context.request_id, backend = %guard.backend.addr, "Backend ejected after consecutive 5xx responses");
+                }
             } else {
-                debug!(
-                    upstream = %upstream_name,
-                    client_ip = %context.client_ip,
-                    "Empty request body, skipping WAF check"
-                );
+                guard.backend.health.report_success();
             }
         }
 
+        let status = upstream_response.status.as_u16();
+        if self.retry_policy(session, context).is_some_and(|retry| retry.on_status.contains(&status)) {
+            return Err(Error::explain(RETRY_STATUS_ERROR, format!("upstream responded with {}", status)));
+        }
         Ok(())
     }
-}
 
-pub fn run_server(config: Config) -> Result<(), Box<dyn std::error::Error>> {
-    let mut server_threads = vec![];
-    
-    // Создаем менеджер прокси
-    let proxy_manager = Arc::new(ProxyManager::new(config.clone()));
-    
-    for (server_name, server_config) in config.get_servers() {
-        let proxy_manager_clone = proxy_manager.clone();
-        let server_name_clone = server_name.clone();
-        let server_config_addr = server_config.addr.clone(); // Клонируем addr
-        
-        let thread = std::thread::spawn(move || {
-            // Получаем прокси из менеджера
-            let proxy = proxy_manager_clone.get_proxy(&server_name_clone)
-                .expect(&format!("Proxy not found for server: {}", server_name_clone));
-            
-            info!("Starting server '{}' on {}", server_name_clone, server_config_addr);
-            info!("{}", proxy.get_waf_info());
-            
-            // Клонируем переменные для внутреннего потока
-            let sighup_proxy = proxy.clone();
-            let server_name_for_sighup = server_name_clone.clone();
-            
-            std::thread::spawn(move || {
-                let rt = tokio::runtime::Runtime::new().unwrap();
-                rt.block_on(async {
-                    info!("Starting SIGHUP watcher for server '{}'", server_name_for_sighup);
-                    sighup_proxy.watch_all_sighup().await;
-                });
+    async fn response_filter(
+        &self,
+        session: &mut Session,
+        upstream_response: &mut ResponseHeader,
+        ctx: &mut Self::CTX,
+    ) -> Result<()> {
+        let context = match ctx {
+            Some(ctx) => ctx,
+            None => return Ok(()),
+        };
+
+        let upstream_name = match &context.upstream_name {
+            Some(name) => name.clone(),
+            None => return Ok(()),
+        };
+
+        let upstream = match self.config.get_upstream(&upstream_name) {
+            Some(upstream) if upstream.inspect_response && context.mode != WafMode::Off => upstream,
+            _ => return Ok(()),
+        };
+
+        let waf_tx = match context.waf_tx.as_mut() {
+            Some(tx) => tx,
+            None => return Ok(()),
+        };
+
+        let request_headers = session.req_header();
+        let method = request_headers.method.as_str();
+        let uri = request_headers.uri.to_string();
+        let status = upstream_response.status.as_u16();
+
+        let started = Instant::now();
+        let waf_result = waf_tx.process_response_headers(status, &upstream_response.headers);
+        metrics::observe_waf(&upstream_name, "response_headers", started);
+
+        if !waf_result.allowed {
+            let blocked = context.mode == WafMode::Block;
+            warn!(
+                request_id = %context.request_id,
+                upstream = %upstream_name,
+                method = %method,
+                uri = %uri,
+                status = status,
+                rule_id = %waf_result.rule_id,
+                severity = ?waf_result.matched_rule.as_ref().and_then(|r| r.severity_name()),
+                reason = %waf_result.reason,
+                blocked = blocked,
+                "WAF violation in response headers"
+            );
+
+            context.violations.push(WafViolation {
+                rule_id: waf_result.rule_id,
+                reason: waf_result.reason.clone(),
+                blocked,
+                timestamp: Utc::now(),
+                source: "response_headers".to_string(),
+                rules: waf_result.rules.clone(),
             });
 
-            let mut server = pingora::server::Server::new(None).expect("Failed to create server");
-            server.bootstrap();
+            if !blocked {
+                return Ok(());
+            }
+
+            // Тело ответа бэкенда будет отброшено в response_body_filter
+            context.response_blocked = true;
+            // Тело бэкенда ещё не пришло, поэтому страница блокировки не подставляется
+            let mut blocked = match redirect_location(&waf_result) {
+                Some((status, location)) => {
+                    let mut redirect = ResponseHeader::build(status, Some(2))?;
+                    redirect.insert_header("Location", location)?;
+                    redirect
+                }
+                None => {
+                    let status = self.block_page(Some(&upstream_name)).waf_status(waf_result.status);
+                    ResponseHeader::build(status, Some(1))?
+                }
+            };
+            blocked.insert_header("Content-Length", "0")?;
+            *upstream_response = blocked;
+            return Ok(());
+        }
+
+        let content_type = upstream_response
+            .headers
+            .get("content-type")
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or("")
+            .to_lowercase();
+
+        // У ответов на HEAD, 1xx, 204 и 304 тела нет, их framing не трогаем
+        let bodiless = method == "HEAD" || status < 200 || status == 204 || status == 304;
+        if !bodiless && INSPECTED_RESPONSE_TYPES.iter().any(|t| content_type.starts_with(t)) {
+            let max_size = upstream.max_response_body_size.unwrap_or(DEFAULT_MAX_RESPONSE_BODY_SIZE);
+            context.response_inspector = BodyInspector::new(max_size, true);
+            context.inspect_response_body = true;
+
+            // Тело удерживается до вердикта, поэтому длина может измениться
+            upstream_response.remove_header("Content-Length");
+            upstream_response.insert_header("Transfer-Encoding", "chunked")?;
+        }
+
+        Ok(())
+    }
+
+    fn response_body_filter(
+        &self,
+        session: &mut Session,
+        body: &mut Option<Bytes>,
+        end_of_stream: bool,
+        ctx: &mut Self::CTX,
+    ) -> Result<Option<Duration>> {
+        let context = match ctx {
+            Some(ctx) => ctx,
+            None => return Ok(None),
+        };
+
+        if context.response_blocked {
+            *body = None;
+            return Ok(None);
+        }
+
+        if !context.inspect_response_body {
+            return Ok(None);
+        }
+
+        if let Some(chunk) = body.take() {
+            if let Err(e) = context.response_inspector.append_chunk(&chunk) {
+                // Слишком большой ответ отдаём без проверки тела
+                warn!(
+                    request_id = %context.request_id,
+                    upstream = ?context.upstream_name,
+                    "Response body not inspected: {}", e
+                );
+                let mut buffered = context.response_inspector.get_body();
+                buffered.extend_from_slice(&chunk);
+                context.response_inspector.clear();
+                context.inspect_response_body = false;
+                *body = Some(Bytes::from(buffered));
+                return Ok(None);
+            }
+        }
+
+        if !end_of_stream {
+            return Ok(None);
+        }
+
+        context.inspect_response_body = false;
+        let full_body = context.response_inspector.get_body();
+        context.response_inspector.clear();
 
-            let mut proxy_service = pingora::proxy::http_proxy_service(
-                &server.configuration,
-                proxy.as_ref().clone(),
+        let started = Instant::now();
+        let waf_result = match context.waf_tx.as_mut() {
+            Some(waf_tx) => waf_tx.process_response_body(&full_body),
+            None => {
+                *body = Some(Bytes::from(full_body));
+                return Ok(None);
+            }
+        };
+        if let Some(upstream_name) = &context.upstream_name {
+            metrics::observe_waf(upstream_name, "response_body", started);
+        }
+
+        let request_headers = session.req_header();
+        let method = request_headers.method.as_str();
+        let uri = request_headers.uri.to_string();
+
+        let blocked = !waf_result.allowed && context.mode == WafMode::Block;
+        if !waf_result.allowed {
+            warn!(
+                request_id = %context.request_id,
+                upstream = ?context.upstream_name,
+                method = %method,
+                uri = %uri,
+                body_size = full_body.len(),
+                rule_id = %waf_result.rule_id,
+                severity = ?waf_result.matched_rule.as_ref().and_then(|r| r.severity_name()),
+                reason = %waf_result.reason,
+                blocked = blocked,
+                "WAF violation in response body"
             );
 
-            proxy_service.add_tcp(&server_config_addr);
-            server.add_service(proxy_service);
+            context.violations.push(WafViolation {
+                rule_id: waf_result.rule_id,
+                reason: waf_result.reason.clone(),
+                blocked,
+                timestamp: Utc::now(),
+                source: "response_body".to_string(),
+                rules: waf_result.rules.clone(),
+            });
+        }
 
-            info!(address = %server_config_addr, "Proxy server '{}' started", server_name_clone);
-            server.run_forever();
-        });
-        
-        server_threads.push(thread);
+        if blocked {
+            // Статус уже отправлен клиенту и подменить его нельзя: тело не отдаётся,
+            // а chunked-ответ обрывается без завершающего блока, поэтому клиент
+            // видит его незавершённым, а не успешным
+            return Err(pingora::Error::new_str("WAF violation in response body"));
+        }
+
+        *body = Some(Bytes::from(full_body));
+        Ok(None)
     }
 
+    async fn logging(&self, session: &mut Session, _e: Option<&Error>, ctx: &mut Self::CTX) {
+        let Some(context) = ctx.as_mut() else {
+            return;
+        };
+
+        if let Some(waf_tx) = context.waf_tx.as_mut() {
+            waf_tx.process_logging();
+        }
+
+        let upstream_name = context.upstream_name.as_deref().unwrap_or("none");
+        metrics::inc_requests(&self.server_name, upstream_name);
+        for violation in &context.violations {
+            metrics::inc_waf_violation(upstream_name, violation.rule_id, &violation.source, violation.blocked);
+        }
+
+        // В бан идут только заблокированные запросы, режим detect клиента не банит
+        let blocked_count = context.violations.iter().filter(|v| v.blocked).count();
+        if blocked_count > 0 {
+            ban_list().record_violations(&context.client_ip, blocked_count);
+        }
+
+        if let Some(audit) = audit_log() {
+            let blocked = context.violations.iter().any(|v| v.blocked);
+            if blocked || (!context.violations.is_empty() && audit.config().log_detected) {
+                audit.write(&self.build_audit_record(session, context, audit));
+            }
+        }
+    }
+
+    async fn connected_to_upstream(
+        &self,
+        _session: &mut Session,
+        _reused: bool,
+        peer: &HttpPeer,
+        #[cfg(unix)] _fd: std::os::unix::io::RawFd,
+        #[cfg(windows)] _sock: std::os::windows::io::RawSocket,
+        digest: Option<&Digest>,
+        ctx: &mut Self::CTX,
+    ) -> Result<()> {
+        let Some(context) = ctx.as_ref() else {
+            return Ok(());
+        };
+        let Some(tls) = context.upstream_name.as_ref().and_then(|name| self.upstream_tls.get(name)) else {
+            return Ok(());
+        };
+        if !peer.is_tls() {
+            return Ok(());
+        }
+        if let Err(e) = tls.check_version(digest) {
+            warn!(request_id = %context.request_id, backend = %peer, error = %e, "Upstream TLS version rejected");
+            return Err(Error::explain(ErrorType::HTTPStatus(502), e));
+        }
+        Ok(())
+    }
+
+    fn fail_to_connect(
+        &self,
+        session: &mut Session,
+        peer: &HttpPeer,
+        ctx: &mut Self::CTX,
+        mut e: Box<Error>,
+    ) -> Box<Error> {
+        let Some(context) = ctx.as_mut() else {
+            return e;
+        };
+        record_breaker(context, false);
+        if let Some(guard) = context.backend.as_ref() {
+            if guard.backend.health.report_failure() {
+                warn!(request_id = %context.request_id, backend = %peer, "Backend ejected after consecutive connect failures");
+            }
+        }
+
+        if self.retry_policy(session, context).is_some_and(|retry| retry.on_connect_failure) {
+            context.retries += 1;
+            warn!(request_id = %context.request_id, backend = %peer, error = %e, retry = context.retries, "Retrying request after connect failure");
+            e.set_retry(true);
+        }
+        e
+    }
+
+    fn error_while_proxy(
+        &self,
+        peer: &HttpPeer,
+        session: &mut Session,
+        e: Box<Error>,
+        ctx: &mut Self::CTX,
+        client_reused: bool,
+    ) -> Box<Error> {
+        let mut e = e.more_context(format!("Peer: {}", peer));
+        let truncated = session.as_ref().retry_buffer_truncated();
+
+        match ctx.as_mut() {
+            Some(context) if e.etype == RETRY_STATUS_ERROR => {
+                context.retries += 1;
+                warn!(request_id = %context.request_id, backend = %peer, retry = context.retries, "Retrying request after upstream status");
+                e.set_retry(!truncated);
+            }
+            // Как в Pingora по умолчанию: переподключаемся, если бэкенд закрыл соединение из пула
+            context => {
+                if let Some(context) = context.filter(|_| *e.esource() == ErrorSource::Upstream) {
+                    record_breaker(context, false);
+                }
+                e.retry.decide_reuse(client_reused && !truncated);
+            }
+        }
+        e
+    }
+}
+
+// Статус и Location для действия redirect; 302, если правило не задало 3xx.
+// Адрес, который нельзя передать в заголовке, игнорируется
+fn redirect_location(waf_result: &WafCheckResult) -> Option<(u16, &str)> {
+    let url = waf_result.redirect_url.as_deref()?;
+    if url.is_empty() || !url.bytes().all(|b| b.is_ascii_graphic()) {
+        return None;
+    }
+    let status = if (300..=399).contains(&waf_result.status) { waf_result.status } else { 302 };
+    Some((status, url))
+}
+
+// Результат попытки для circuit breaker'а выбранного бэкенда; учитывается только первый
+fn record_breaker(context: &mut RequestContext, success: bool) {
+    let Some(guard) = context.backend.as_ref().filter(|_| !context.breaker_recorded) else {
+        return;
+    };
+    context.breaker_recorded = true;
+    let latency = context.upstream_started.map(|started| started.elapsed());
+    if let Some(state) = guard.backend.breaker.record(success, latency) {
+        warn!(
+            request_id = %context.request_id,
+            upstream = ?context.upstream_name,
+            backend = %guard.backend.addr,
+            state = state.as_str(),
+            "Circuit breaker state changed"
+        );
+    }
+}
+
+pub fn run_server(config: Config) -> Result<(), Box<dyn std::error::Error>> {
     let admin_port = config.get_admin_port();
-    
-    // Запускаем admin сервер
-    std::thread::spawn(move || {
-        let rt = tokio::runtime::Runtime::new().unwrap();
-        rt.block_on(async {
-            run_admin_server(admin_port, proxy_manager).await;
-        });
-    });
 
-    info!("All proxy servers running");
-    
-    for thread in server_threads {
-        let _ = thread.join();
+    // Фоновые задачи: проверки здоровья, SIGHUP, admin API
+    let runtime = tokio::runtime::Runtime::new()?;
+    let server_manager = Arc::new(ServerManager::new(config, runtime.handle().clone())?);
+
+    for proxy in server_manager.current().proxies.values() {
+        info!("{}", proxy.get_waf_info());
     }
-    
+    server_manager.start_listeners();
+
+    runtime.spawn(server_manager.clone().watch_sighup());
+    runtime.spawn(run_admin_server(admin_port, server_manager));
+
+    info!("All proxy servers running");
+    runtime.block_on(std::future::pending::<()>());
+
     Ok(())
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn intervention(status: u16, redirect_url: Option<&str>) -> WafCheckResult {
+        let mut result = WafCheckResult::error(String::new());
+        result.allowed = false;
+        result.status = status;
+        result.redirect_url = redirect_url.map(|url| url.to_string());
+        result
+    }
+
+    #[test]
+    fn redirect_uses_3xx_only_with_url() {
+        let url = "https://example.com/blocked";
+        assert_eq!(redirect_location(&intervention(301, Some(url))), Some((301, url)));
+        assert_eq!(redirect_location(&intervention(0, Some(url))), Some((302, url)));
+        assert_eq!(redirect_location(&intervention(403, Some(url))), Some((302, url)));
+        assert_eq!(redirect_location(&intervention(302, None)), None);
+        assert_eq!(redirect_location(&intervention(302, Some(""))), None);
+    }
+}
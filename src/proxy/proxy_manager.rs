@@ -2,25 +2,187 @@ use std::sync::Arc;
 use std::collections::HashMap;
 
 use pingora::Result;
+use tokio::runtime::Handle;
 
 use crate::config::config::Config;
+use crate::proxy::access::{watch_access_list, AccessList};
+use crate::proxy::balancer::LoadBalancer;
+use crate::proxy::health::run_health_checks;
 use crate::proxy::proxy::MyProxy;
+use crate::proxy::rate_limit::RateLimiter;
 
 pub struct ProxyManager {
     pub proxies: HashMap<String, Arc<MyProxy>>,
+    // Балансировщики общие для всех серверов, чтобы состояние здоровья не дублировалось
+    pub balancers: HashMap<String, Arc<LoadBalancer>>,
+    // Лимиты upstream'ов тоже общие для всех серверов
+    pub rate_limiters: HashMap<String, Arc<RateLimiter>>,
+    // Лимиты серверов по имени сервера
+    pub server_rate_limiters: HashMap<String, Arc<RateLimiter>>,
+    // Списки доступа upstream'ов; при перезагрузке перечитываются заново
+    pub access_lists: HashMap<String, Arc<AccessList>>,
     pub config: Config,
 }
 
 impl ProxyManager {
-    pub fn new(config: Config) -> Self {
+    pub fn new(config: Config) -> Result<Self, String> {
+        Self::build(config, None)
+    }
+
+    // Новое состояние для перезагруженного конфига. Балансировщики неизменённых
+    // upstream'ов переиспользуются, чтобы не терять состояние здоровья и счётчики.
+    pub fn rebuild(&self, config: Config) -> Result<Self, String> {
+        Self::build(config, Some(self))
+    }
+
+    fn build(config: Config, previous: Option<&ProxyManager>) -> Result<Self, String> {
         let mut proxies = HashMap::new();
+
+        let balancers: HashMap<String, Arc<LoadBalancer>> = config
+            .upstreams
+            .iter()
+            .map(|(name, upstream)| {
+                let reused = previous
+                    .filter(|prev| prev.config.get_upstream(name) == Some(upstream))
+                    .and_then(|prev| prev.balancers.get(name).cloned());
+                let balancer = reused.unwrap_or_else(|| Arc::new(LoadBalancer::new(upstream)));
+                (name.clone(), balancer)
+            })
+            .collect();
+
+        let rate_limiters: HashMap<String, Arc<RateLimiter>> = config
+            .upstreams
+            .iter()
+            .map(|(name, upstream)| {
+                let reused = previous
+                    .filter(|prev| {
+                        prev.config.get_upstream(name).map(|u| &u.rate_limits) == Some(&upstream.rate_limits)
+                    })
+                    .and_then(|prev| prev.rate_limiters.get(name).cloned());
+                let limiter = reused.unwrap_or_else(|| Arc::new(RateLimiter::new(&upstream.rate_limits)));
+                (name.clone(), limiter)
+            })
+            .collect();
+
+        let server_rate_limiters: HashMap<String, Arc<RateLimiter>> = config
+            .get_servers()
+            .iter()
+            .map(|(name, server)| {
+                let reused = previous
+                    .filter(|prev| {
+                        prev.config.get_server(name).map(|s| &s.rate_limits) == Some(&server.rate_limits)
+                    })
+                    .and_then(|prev| prev.server_rate_limiters.get(name).cloned());
+                let limiter = reused.unwrap_or_else(|| Arc::new(RateLimiter::new(&server.rate_limits)));
+                (name.clone(), limiter)
+            })
+            .collect();
+
+        let mut access_lists = HashMap::new();
+        for (name, upstream) in &config.upstreams {
+            if let Some(access) = &upstream.access {
+                let list = AccessList::new(access, &config)
+                    .map_err(|e| format!("Access list of upstream '{}': {}", name, e))?;
+                access_lists.insert(name.clone(), Arc::new(list));
+            }
+        }
         
         for server_name in config.get_servers().keys() {
-            let proxy = MyProxy::new_for_server(config.clone(), server_name);
+            let proxy = MyProxy::new_for_server(
+                config.clone(),
+                server_name,
+                &balancers,
+                &rate_limiters,
+                &server_rate_limiters,
+                &access_lists,
+            )?;
             proxies.insert(server_name.clone(), Arc::new(proxy));
         }
         
-        Self { proxies, config }
+        Ok(Self { proxies, balancers, rate_limiters, server_rate_limiters, access_lists, config })
+    }
+
+    // Запускает проверки здоровья для балансировщиков, которых не было в previous
+    pub fn spawn_health_checks(&self, runtime: &Handle, previous: Option<&ProxyManager>) {
+        for (name, balancer) in &self.balancers {
+            let reused = previous
+                .and_then(|prev| prev.balancers.get(name))
+                .is_some_and(|prev| Arc::ptr_eq(prev, balancer));
+            if balancer.health_check().is_some() && !reused {
+                runtime.spawn(run_health_checks(name.clone(), Arc::downgrade(balancer)));
+            }
+        }
+    }
+
+    // Запускает слежение за файлами списков доступа; задачи прежнего состояния
+    // завершаются сами, когда их списки освобождаются
+    pub fn spawn_access_watchers(&self, runtime: &Handle) {
+        let server_lists = self.proxies.iter()
+            .filter_map(|(name, proxy)| proxy.server_access().map(|list| (format!("servers.{}", name), list)));
+        let upstream_lists = self.access_lists.iter()
+            .map(|(name, list)| (format!("upstreams.{}", name), list));
+
+        for (name, list) in server_lists.chain(upstream_lists) {
+            if list.has_files() {
+                runtime.spawn(watch_access_list(name, Arc::downgrade(list)));
+            }
+        }
+    }
+
+    // Прокси сервера, который сейчас настроен на этот адрес
+    pub fn get_proxy_for_addr(&self, addr: &str) -> Option<Arc<MyProxy>> {
+        self.config
+            .get_servers()
+            .iter()
+            .find(|(_, server)| server.addr == addr)
+            .and_then(|(name, _)| self.proxies.get(name).cloned())
+    }
+
+    pub fn get_upstreams_health(&self) -> String {
+        let mut info = String::new();
+        for (name, balancer) in &self.balancers {
+            info.push_str(&format!("=== Upstream: {} ({:?}) ===\n", name, balancer.method()));
+            for backend in balancer.backends() {
+                let state = if backend.health.is_ejected() {
+                    "ejected"
+                } else if backend.health.is_healthy() {
+                    "healthy"
+                } else {
+                    "unhealthy"
+                };
+                info.push_str(&format!(
+                    "{} {} weight={} active={} fails={} breaker={}\n",
+                    backend.addr,
+                    state,
+                    backend.weight,
+                    backend.active_connections(),
+                    backend.health.passive_failures(),
+                    backend.breaker.describe(),
+                ));
+            }
+        }
+        info
+    }
+
+    // Состояние circuit breaker'ов upstream'ов, где они включены
+    pub fn get_circuit_breakers(&self) -> String {
+        let mut info = String::new();
+        for (name, balancer) in &self.balancers {
+            let Some(breaker) = self.config.get_upstream(name).and_then(|u| u.circuit_breaker.as_ref()) else {
+                continue;
+            };
+            info.push_str(&format!(
+                "=== Upstream: {} (error_rate={} min_requests={} open_secs={}) ===\n",
+                name, breaker.error_rate, breaker.min_requests, breaker.open_secs
+            ));
+            for backend in balancer.backends() {
+                info.push_str(&format!("{} {}\n", backend.addr, backend.breaker.describe()));
+            }
+        }
+        if info.is_empty() {
+            info.push_str("No circuit breakers configured\n");
+        }
+        info
     }
     
     pub fn get_proxy(&self, server_name: &str) -> Option<Arc<MyProxy>> {
@@ -67,7 +229,7 @@ impl ProxyManager {
     }
         
     pub fn get_server_info(&self, server_name: &str) -> Option<String> {
-        self.proxies.get(server_name)
-        .map(|proxy| proxy.get_waf_info())
+        self.get_proxy(server_name)
+            .map(|proxy| proxy.get_waf_info())
     }
 }
\ No newline at end of file
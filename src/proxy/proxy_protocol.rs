@@ -0,0 +1,204 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::os::unix::io::AsRawFd;
+use std::sync::LazyLock;
+use std::time::Duration;
+
+use parking_lot::Mutex;
+use pingora::server::configuration::{Opt, ServerConf};
+use pingora::server::{Fds, Server};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::timeout;
+use tracing::{debug, error, info, warn};
+
+// Сколько ждём заголовок PROXY после установки соединения
+const HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+// Максимальная длина строки v1 вместе с CRLF
+const V1_MAX_LEN: usize = 107;
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+
+// Pingora не разбирает PROXY protocol, поэтому для таких серверов на публичном
+// адресе слушает ретранслятор: он снимает заголовок и передаёт поток во внутренний
+// листенер Pingora на 127.0.0.1. Адрес из заголовка запоминается по локальному
+// адресу исходящего соединения, который Pingora видит как адрес клиента.
+static CONNECTIONS: LazyLock<Mutex<HashMap<SocketAddr, ProxiedConnection>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+#[derive(Debug, Clone, Copy)]
+pub struct ProxiedConnection {
+    // Кто на самом деле подключился к публичному адресу
+    pub peer: SocketAddr,
+    // Адрес клиента из заголовка; None для LOCAL и UNKNOWN
+    pub source: Option<SocketAddr>,
+}
+
+// Соединение ретранслятора по адресу, который Pingora видит как адрес клиента
+pub fn lookup(relay_addr: &SocketAddr) -> Option<ProxiedConnection> {
+    CONNECTIONS.lock().get(relay_addr).copied()
+}
+
+// Внутренний листенер Pingora на свободном порту loopback. Сокет не закрывается
+// до передачи в Pingora, поэтому порт не может занять другой процесс
+pub fn internal_listener() -> std::io::Result<(std::net::TcpListener, SocketAddr)> {
+    let listener = std::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?;
+    // Pingora оборачивает сокет в tokio, которому нужен неблокирующий режим
+    listener.set_nonblocking(true)?;
+    let addr = listener.local_addr()?;
+    Ok((listener, addr))
+}
+
+// Сервер Pingora, который получает уже открытый внутренний сокет так же, как при
+// graceful upgrade: через свой unix-сокет upgrade_sock во время bootstrap().
+// Дальше он работает в обычном жизненном цикле Pingora, с graceful shutdown
+pub fn internal_server(listener: std::net::TcpListener, addr: SocketAddr) -> Result<Server, String> {
+    let mut conf = ServerConf::new().ok_or("failed to create Pingora configuration")?;
+    conf.upgrade_sock = std::env::temp_dir()
+        .join(format!("centaur-{}-{}.sock", std::process::id(), addr.port()))
+        .to_string_lossy()
+        .into_owned();
+    let sock = conf.upgrade_sock.clone();
+    let opt = Opt { upgrade: true, ..Default::default() };
+    let server = Server::new_with_opt_and_conf(opt, conf);
+
+    // bootstrap() ждёт сокет, поэтому он отправляется из отдельного потока;
+    // у Pingora остаётся своя копия дескриптора, наша закрывается вместе с listener
+    std::thread::spawn(move || {
+        let mut fds = Fds::new();
+        fds.add(addr.to_string(), listener.as_raw_fd());
+        if let Err(e) = fds.send_to_sock(sock.as_str()) {
+            error!(internal = %addr, error = %e, "Failed to pass internal listener to Pingora");
+        }
+    });
+    Ok(server)
+}
+
+pub async fn run_relay(addr: String, internal: SocketAddr) {
+    let listener = match TcpListener::bind(&addr).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!(address = %addr, error = %e, "Failed to bind PROXY protocol listener");
+            return;
+        }
+    };
+    info!(address = %addr, internal = %internal, "PROXY protocol listener started");
+
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(conn) => conn,
+            Err(e) => {
+                warn!(address = %addr, error = %e, "Failed to accept connection");
+                continue;
+            }
+        };
+        tokio::spawn(async move {
+            if let Err(e) = relay(stream, peer, internal).await {
+                debug!(peer = %peer, error = %e, "PROXY protocol connection dropped");
+            }
+        });
+    }
+}
+
+async fn relay(mut client: TcpStream, peer: SocketAddr, internal: SocketAddr) -> Result<(), String> {
+    let mut buf = Vec::with_capacity(256);
+    let (header_len, source) = timeout(HEADER_TIMEOUT, read_header(&mut client, &mut buf))
+        .await
+        .map_err(|_| "timed out waiting for PROXY header".to_string())??;
+
+    let mut upstream = TcpStream::connect(internal).await.map_err(|e| e.to_string())?;
+    let local = upstream.local_addr().map_err(|e| e.to_string())?;
+    CONNECTIONS.lock().insert(local, ProxiedConnection { peer, source });
+
+    let result = async {
+        // Данные, прочитанные вместе с заголовком
+        upstream.write_all(&buf[header_len..]).await?;
+        tokio::io::copy_bidirectional(&mut client, &mut upstream).await
+    }
+    .await;
+
+    CONNECTIONS.lock().remove(&local);
+    result.map(|_| ()).map_err(|e| e.to_string())
+}
+
+async fn read_header(stream: &mut TcpStream, buf: &mut Vec<u8>) -> Result<(usize, Option<SocketAddr>), String> {
+    let mut chunk = [0u8; 512];
+    loop {
+        if let Some(header) = parse_header(buf)? {
+            return Ok(header);
+        }
+        let n = stream.read(&mut chunk).await.map_err(|e| e.to_string())?;
+        if n == 0 {
+            return Err("connection closed before PROXY header".to_string());
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    }
+}
+
+// Длина заголовка и адрес клиента; Ok(None) - данных пока недостаточно
+fn parse_header(buf: &[u8]) -> Result<Option<(usize, Option<SocketAddr>)>, String> {
+    let prefix = buf.len().min(V2_SIGNATURE.len());
+    if buf[..prefix] == V2_SIGNATURE[..prefix] {
+        return if prefix < V2_SIGNATURE.len() { Ok(None) } else { parse_v2(buf) };
+    }
+    let prefix = buf.len().min(6);
+    if buf[..prefix] == b"PROXY "[..prefix] {
+        return if prefix < 6 { Ok(None) } else { parse_v1(buf) };
+    }
+    Err("missing PROXY protocol header".to_string())
+}
+
+// "PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\n"
+fn parse_v1(buf: &[u8]) -> Result<Option<(usize, Option<SocketAddr>)>, String> {
+    let Some(end) = buf.windows(2).position(|w| w == b"\r\n") else {
+        return if buf.len() >= V1_MAX_LEN { Err("PROXY v1 header too long".to_string()) } else { Ok(None) };
+    };
+    let line = std::str::from_utf8(&buf[..end]).map_err(|_| "invalid PROXY v1 header".to_string())?;
+    let parts: Vec<&str> = line.split(' ').collect();
+
+    let source = match parts.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => None,
+        ["PROXY", "TCP4" | "TCP6", src, _dst, src_port, _dst_port] => {
+            let ip: IpAddr = src.parse().map_err(|_| format!("invalid source address '{}'", src))?;
+            let port: u16 = src_port.parse().map_err(|_| format!("invalid source port '{}'", src_port))?;
+            Some(SocketAddr::new(ip, port))
+        }
+        _ => return Err(format!("invalid PROXY v1 header '{}'", line)),
+    };
+    Ok(Some((end + 2, source)))
+}
+
+fn parse_v2(buf: &[u8]) -> Result<Option<(usize, Option<SocketAddr>)>, String> {
+    if buf.len() < 16 {
+        return Ok(None);
+    }
+    let version = buf[12] >> 4;
+    let command = buf[12] & 0x0f;
+    if version != 2 {
+        return Err(format!("unsupported PROXY protocol version {}", version));
+    }
+    let len = 16 + u16::from_be_bytes([buf[14], buf[15]]) as usize;
+    if buf.len() < len {
+        return Ok(None);
+    }
+
+    // LOCAL: соединение самого прокси, например проверка здоровья
+    if command == 0 {
+        return Ok(Some((len, None)));
+    }
+    let addrs = &buf[16..len];
+    let source = match buf[13] >> 4 {
+        // AF_INET
+        1 if addrs.len() >= 12 => {
+            let ip = Ipv4Addr::new(addrs[0], addrs[1], addrs[2], addrs[3]);
+            Some(SocketAddr::new(ip.into(), u16::from_be_bytes([addrs[8], addrs[9]])))
+        }
+        // AF_INET6
+        2 if addrs.len() >= 36 => {
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(&addrs[..16]);
+            Some(SocketAddr::new(Ipv6Addr::from(octets).into(), u16::from_be_bytes([addrs[32], addrs[33]])))
+        }
+        _ => None,
+    };
+    Ok(Some((len, source)))
+}
@@ -0,0 +1,176 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+use pingora::http::RequestHeader;
+
+use crate::config::config::{RateLimitAlgorithm, RateLimitConfig, RateLimitKey};
+
+// После скольких ключей начинаем вычищать неактивные
+const PRUNE_THRESHOLD: usize = 10_000;
+
+// Запрос отклонён политикой
+pub struct RateLimited {
+    pub policy: String,
+    pub retry_after: u64,
+}
+
+enum Bucket {
+    TokenBucket { tokens: f64, last: Instant },
+    // Скользящее окно: счётчики текущего и предыдущего окна
+    SlidingWindow { start: Instant, previous: u32, current: u32 },
+}
+
+impl Bucket {
+    fn last_seen(&self) -> Instant {
+        match self {
+            Bucket::TokenBucket { last, .. } => *last,
+            Bucket::SlidingWindow { start, .. } => *start,
+        }
+    }
+}
+
+struct Buckets {
+    entries: HashMap<String, Bucket>,
+    last_prune: Instant,
+}
+
+struct Policy {
+    config: RateLimitConfig,
+    period: Duration,
+    buckets: Mutex<Buckets>,
+}
+
+impl Policy {
+    // Ключ, по которому считаются запросы; None - политика к запросу не применяется.
+    // path - после normalize_path, иначе "//login" или "/%6cogin" обходят лимит на /login
+    fn key(&self, req: &RequestHeader, path: &str, client_ip: &str, upstream: &str) -> Option<String> {
+        if let Some(prefix) = &self.config.path_prefix {
+            if !path.starts_with(prefix.as_str()) {
+                return None;
+            }
+        }
+
+        match self.config.key {
+            RateLimitKey::Ip => Some(client_ip.to_string()),
+            RateLimitKey::Header => self
+                .config
+                .header
+                .as_deref()
+                .and_then(|name| req.headers.get(name))
+                .and_then(|v| v.to_str().ok())
+                .map(|v| v.to_string()),
+            RateLimitKey::Path => Some(self.config.path_prefix.clone().unwrap_or_default()),
+            RateLimitKey::Upstream => Some(upstream.to_string()),
+        }
+    }
+
+    // Возвращает через сколько секунд можно повторить, если лимит исчерпан
+    fn acquire(&self, key: String, now: Instant) -> Option<u64> {
+        let limit = self.config.requests.max(1);
+        let period = self.period.as_secs_f64();
+
+        let mut buckets = self.buckets.lock();
+        if buckets.entries.len() > PRUNE_THRESHOLD && now.duration_since(buckets.last_prune) > self.period {
+            let idle = self.period * 2;
+            buckets.entries.retain(|_, b| now.duration_since(b.last_seen()) < idle);
+            buckets.last_prune = now;
+        }
+
+        match self.config.algorithm {
+            RateLimitAlgorithm::TokenBucket => {
+                let capacity = self.config.burst.unwrap_or(limit).max(1) as f64;
+                let rate = limit as f64 / period;
+                let bucket = buckets.entries.entry(key).or_insert(Bucket::TokenBucket { tokens: capacity, last: now });
+                let Bucket::TokenBucket { tokens, last } = bucket else {
+                    return None;
+                };
+
+                *tokens = (*tokens + now.duration_since(*last).as_secs_f64() * rate).min(capacity);
+                *last = now;
+                if *tokens >= 1.0 {
+                    *tokens -= 1.0;
+                    None
+                } else {
+                    Some(((1.0 - *tokens) / rate).ceil().max(1.0) as u64)
+                }
+            }
+            RateLimitAlgorithm::SlidingWindow => {
+                let bucket = buckets
+                    .entries
+                    .entry(key)
+                    .or_insert(Bucket::SlidingWindow { start: now, previous: 0, current: 0 });
+                let Bucket::SlidingWindow { start, previous, current } = bucket else {
+                    return None;
+                };
+
+                let mut elapsed = now.duration_since(*start).as_secs_f64();
+                if elapsed >= period {
+                    // Сдвигаем окно; если прошло больше двух окон, предыдущее пустое
+                    *previous = if elapsed < 2.0 * period { *current } else { 0 };
+                    *current = 0;
+                    let windows = (elapsed / period).floor();
+                    *start += Duration::from_secs_f64(windows * period);
+                    elapsed -= windows * period;
+                }
+
+                let weight = 1.0 - elapsed / period;
+                let estimate = *previous as f64 * weight + *current as f64;
+                if estimate < limit as f64 {
+                    *current += 1;
+                    None
+                } else {
+                    Some((period - elapsed).ceil().max(1.0) as u64)
+                }
+            }
+        }
+    }
+}
+
+// Набор политик ограничения частоты запросов сервера или upstream'а
+pub struct RateLimiter {
+    policies: Vec<Policy>,
+}
+
+impl RateLimiter {
+    pub fn new(configs: &[RateLimitConfig]) -> Self {
+        let now = Instant::now();
+        let policies = configs
+            .iter()
+            .map(|config| Policy {
+                config: config.clone(),
+                period: Duration::from_secs(config.period_secs.max(1)),
+                buckets: Mutex::new(Buckets { entries: HashMap::new(), last_prune: now }),
+            })
+            .collect();
+        Self { policies }
+    }
+
+    // Проверяет все политики; запрос учитывается в каждой подходящей
+    pub fn check(&self, req: &RequestHeader, path: &str, client_ip: &str, upstream: &str) -> Option<RateLimited> {
+        let now = Instant::now();
+        let mut limited: Option<RateLimited> = None;
+
+        for policy in &self.policies {
+            let Some(key) = policy.key(req, path, client_ip, upstream) else {
+                continue;
+            };
+            if let Some(retry_after) = policy.acquire(key, now) {
+                if limited.as_ref().is_none_or(|l| retry_after > l.retry_after) {
+                    limited = Some(RateLimited {
+                        policy: format!(
+                            "{:?} {}/{}s{}",
+                            policy.config.key,
+                            policy.config.requests,
+                            policy.config.period_secs,
+                            policy.config.path_prefix.as_deref().map(|p| format!(" {}", p)).unwrap_or_default()
+                        ),
+                        retry_after,
+                    });
+                }
+            }
+        }
+
+        limited
+    }
+}
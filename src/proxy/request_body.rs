@@ -0,0 +1,248 @@
+use std::io;
+use std::os::fd::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use bytes::{Bytes, BytesMut};
+use tokio::fs::{File, OpenOptions};
+use tokio::io::AsyncWriteExt;
+
+use crate::config::config::RequestBodyMode;
+
+static SPILL_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+// Временный файл с удержанной частью тела; удаляется вместе со структурой
+struct SpillFile {
+    file: File,
+    path: PathBuf,
+    len: usize,
+}
+
+impl SpillFile {
+    async fn create(dir: &Path) -> io::Result<Self> {
+        let name = format!(
+            "centaur-body-{}-{}",
+            std::process::id(),
+            SPILL_COUNTER.fetch_add(1, Ordering::Relaxed)
+        );
+        let path = dir.join(name);
+        let file = OpenOptions::new().read(true).write(true).create_new(true).open(&path).await?;
+        Ok(Self { file, path, len: 0 })
+    }
+
+    async fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        self.file.write_all(data).await?;
+        self.len += data.len();
+        Ok(())
+    }
+
+    // Содержимое файла без чтения в память: после отображения файл удаляется,
+    // страницы остаются доступны, пока жив Bytes
+    async fn into_bytes(mut self) -> io::Result<Bytes> {
+        self.file.flush().await?;
+        if self.len == 0 {
+            return Ok(Bytes::new());
+        }
+        Ok(Bytes::from_owner(SpillMap::new(&self.file, self.len)?))
+    }
+}
+
+impl Drop for SpillFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+// Удержанное тело, отображённое из временного файла. Страницы подгружаются ядром
+// по мере отправки на бэкенд и им же вытесняются, так что тело не копируется
+// в память процесса целиком
+struct SpillMap {
+    ptr: *mut libc::c_void,
+    len: usize,
+}
+
+// Отображение только для чтения; файл после отображения удалён и не меняется
+unsafe impl Send for SpillMap {}
+unsafe impl Sync for SpillMap {}
+
+impl SpillMap {
+    fn new(file: &File, len: usize) -> io::Result<Self> {
+        let ptr = unsafe {
+            libc::mmap(std::ptr::null_mut(), len, libc::PROT_READ, libc::MAP_PRIVATE, file.as_raw_fd(), 0)
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        // Тело читается один раз от начала к концу
+        unsafe { libc::madvise(ptr, len, libc::MADV_SEQUENTIAL) };
+        Ok(Self { ptr, len })
+    }
+}
+
+impl AsRef<[u8]> for SpillMap {
+    fn as_ref(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.ptr as *const u8, self.len) }
+    }
+}
+
+impl Drop for SpillMap {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.ptr, self.len) };
+    }
+}
+
+// Тело запроса на пути к бэкенду: сколько получено, начало для audit log
+// и фрагменты, удерживаемые до решения WAF
+pub struct RequestBody {
+    // Удерживать фрагменты до вердикта (режим buffer при WAF в режиме block)
+    holding: bool,
+    max_size: usize,
+    spill_threshold: Option<usize>,
+    spill_dir: PathBuf,
+    // Фрагменты передаются в ModSecurity; выключается, когда тело больше max_size
+    pub inspecting: bool,
+    // Получено байт
+    pub size: usize,
+    sample: Vec<u8>,
+    sample_limit: usize,
+    held: BytesMut,
+    spill: Option<SpillFile>,
+}
+
+impl RequestBody {
+    // Тело без проверки: до определения upstream'а и при выключенном WAF
+    pub fn disabled() -> Self {
+        Self {
+            holding: false,
+            max_size: 0,
+            spill_threshold: None,
+            spill_dir: std::env::temp_dir(),
+            inspecting: false,
+            size: 0,
+            sample: Vec::new(),
+            sample_limit: 0,
+            held: BytesMut::new(),
+            spill: None,
+        }
+    }
+
+    // sample_limit - сколько байт от начала тела сохранить для audit log
+    pub fn new(
+        mode: RequestBodyMode,
+        blocking: bool,
+        max_size: usize,
+        spill_threshold: Option<usize>,
+        spill_dir: PathBuf,
+        sample_limit: usize,
+    ) -> Self {
+        Self {
+            holding: blocking && mode == RequestBodyMode::Buffer,
+            max_size,
+            spill_threshold,
+            spill_dir,
+            inspecting: true,
+            size: 0,
+            sample: Vec::new(),
+            sample_limit,
+            held: BytesMut::new(),
+            spill: None,
+        }
+    }
+
+    pub fn is_holding(&self) -> bool {
+        self.holding
+    }
+
+    // Начало тела для audit log
+    pub fn sample(&self) -> &[u8] {
+        &self.sample
+    }
+
+    // Сколько байт удержано в памяти и на диске
+    pub fn held_size(&self) -> usize {
+        self.held.len() + self.spill.as_ref().map_or(0, |spill| spill.len)
+    }
+
+    // Учитывает очередной фрагмент; ошибка - тело превысило max_size
+    pub fn record(&mut self, chunk: &[u8]) -> Result<(), String> {
+        self.size += chunk.len();
+        let room = self.sample_limit.saturating_sub(self.sample.len());
+        self.sample.extend_from_slice(&chunk[..room.min(chunk.len())]);
+        if self.size > self.max_size {
+            return Err(format!("Request body exceeds maximum size of {} bytes", self.max_size));
+        }
+        Ok(())
+    }
+
+    // Удерживает фрагмент; сверх spill_threshold удержанное уходит во временный файл
+    pub async fn hold(&mut self, chunk: &[u8]) -> io::Result<()> {
+        let over_threshold = self
+            .spill_threshold
+            .is_some_and(|threshold| self.held.len() + chunk.len() > threshold);
+        if self.spill.is_none() && over_threshold {
+            let mut spill = SpillFile::create(&self.spill_dir).await?;
+            spill.write(&self.held).await?;
+            self.held = BytesMut::new();
+            self.spill = Some(spill);
+        }
+
+        match &mut self.spill {
+            Some(spill) => spill.write(chunk).await,
+            None => {
+                self.held.extend_from_slice(chunk);
+                Ok(())
+            }
+        }
+    }
+
+    // Всё удержанное одним фрагментом (Pingora отправляет на бэкенд один буфер
+    // на вызов request_body_filter); сброшенное на диск не читается в память,
+    // а отображается из файла. Дальше тело идёт на бэкенд без задержки
+    pub async fn release(&mut self) -> io::Result<Bytes> {
+        self.holding = false;
+        match self.spill.take() {
+            Some(spill) => spill.into_bytes().await,
+            None => Ok(self.held.split().freeze()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const THRESHOLD: usize = 64 * 1024;
+
+    fn holding_body() -> RequestBody {
+        RequestBody::new(RequestBodyMode::Buffer, true, usize::MAX, Some(THRESHOLD), std::env::temp_dir(), 0)
+    }
+
+    #[tokio::test]
+    async fn spilled_body_is_released_intact() {
+        let data: Vec<u8> = (0..THRESHOLD * 16 + 123).map(|i| (i % 251) as u8).collect();
+        let mut body = holding_body();
+        for chunk in data.chunks(16 * 1024) {
+            body.record(chunk).unwrap();
+            body.hold(chunk).await.unwrap();
+            // В памяти процесса не больше порога, остальное на диске
+            assert!(body.held.len() <= THRESHOLD);
+        }
+        assert_eq!(body.held_size(), data.len());
+        let path = body.spill.as_ref().map(|spill| spill.path.clone()).unwrap();
+
+        let released = body.release().await.unwrap();
+        assert!(body.held.is_empty());
+        assert!(!path.exists());
+        assert_eq!(released.len(), data.len());
+        assert!(released == data);
+    }
+
+    #[tokio::test]
+    async fn small_body_stays_in_memory() {
+        let mut body = holding_body();
+        body.hold(b"small body").await.unwrap();
+        assert!(body.spill.is_none());
+        assert_eq!(body.release().await.unwrap(), Bytes::from_static(b"small body"));
+        assert!(!body.is_holding());
+    }
+}
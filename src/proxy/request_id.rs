@@ -0,0 +1,24 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+// Длиннее не принимаем от прокси, чтобы не раздувать логи
+const MAX_INCOMING_LEN: usize = 128;
+
+static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+// 32 hex-символа: случайная часть и время со счётчиком, уникальные в пределах процесса
+pub fn generate() -> String {
+    let mut hasher = RandomState::new().build_hasher();
+    let count = COUNTER.fetch_add(1, Ordering::Relaxed);
+    hasher.write_u64(count);
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_nanos() as u64).unwrap_or(0);
+    format!("{:016x}{:016x}", hasher.finish(), nanos ^ count.rotate_left(48))
+}
+
+// Входящий идентификатор пригоден, если он короткий и из печатных символов
+pub fn is_valid(id: &str) -> bool {
+    !id.is_empty() && id.len() <= MAX_INCOMING_LEN && id.bytes().all(|b| b.is_ascii_graphic())
+}
@@ -0,0 +1,269 @@
+use std::collections::HashMap;
+
+use pingora::http::RequestHeader;
+use regex::Regex;
+
+use crate::config::config::{Config, RouteConfig, ServerConfig};
+
+// Имя хоста: точное "api.example.com", маска "*.example.com" (только поддомены)
+// или регулярное выражение "~^api[0-9]+\.example\.com$"
+pub enum HostPattern {
+    Exact(String),
+    // Суффикс вместе с точкой: ".example.com"
+    Wildcard(String),
+    Regex(Regex),
+}
+
+impl HostPattern {
+    pub fn parse(name: &str) -> Result<Self, String> {
+        if let Some(re) = name.strip_prefix('~') {
+            let re = Regex::new(&format!("(?i){}", re)).map_err(|e| format!("invalid host regex '{}': {}", name, e))?;
+            return Ok(HostPattern::Regex(re));
+        }
+        if let Some(suffix) = name.strip_prefix('*') {
+            if !suffix.starts_with('.') || suffix.len() < 2 || suffix.contains('*') {
+                return Err(format!("invalid wildcard host '{}', expected '*.example.com'", name));
+            }
+            return Ok(HostPattern::Wildcard(suffix.to_lowercase()));
+        }
+        if name.is_empty() || name.contains('*') {
+            return Err(format!("invalid host name '{}'", name));
+        }
+        Ok(HostPattern::Exact(name.to_lowercase()))
+    }
+
+    // host - без порта и в нижнем регистре
+    pub fn matches(&self, host: &str) -> bool {
+        match self {
+            HostPattern::Exact(name) => host == name,
+            HostPattern::Wildcard(suffix) => host.len() > suffix.len() && host.ends_with(suffix.as_str()),
+            HostPattern::Regex(re) => re.is_match(host),
+        }
+    }
+}
+
+// Выбор upstream'а сервера по Host: точное имя, затем самая длинная маска,
+// затем регулярные выражения по порядку, иначе default_upstream
+pub struct HostRouter {
+    exact: HashMap<String, String>,
+    wildcard: Vec<(HostPattern, String)>,
+    regex: Vec<(HostPattern, String)>,
+    default: Option<String>,
+}
+
+impl HostRouter {
+    pub fn new(server: &ServerConfig, config: &Config) -> Result<Self, String> {
+        let mut exact = HashMap::new();
+        let mut wildcard = Vec::new();
+        let mut regex = Vec::new();
+
+        for upstream_key in &server.upstreams {
+            let Some(upstream) = config.get_upstream(upstream_key) else {
+                continue;
+            };
+            for name in upstream.host_names() {
+                match HostPattern::parse(name).map_err(|e| format!("upstream '{}': {}", upstream_key, e))? {
+                    HostPattern::Exact(name) => {
+                        // При совпадении имён побеждает upstream, указанный в сервере раньше
+                        exact.entry(name).or_insert_with(|| upstream_key.clone());
+                    }
+                    pattern @ HostPattern::Wildcard(_) => wildcard.push((pattern, upstream_key.clone())),
+                    pattern @ HostPattern::Regex(_) => regex.push((pattern, upstream_key.clone())),
+                }
+            }
+        }
+        wildcard.sort_by_key(|(pattern, _)| match pattern {
+            HostPattern::Wildcard(suffix) => std::cmp::Reverse(suffix.len()),
+            _ => std::cmp::Reverse(0),
+        });
+
+        Ok(Self {
+            exact,
+            wildcard,
+            regex,
+            default: server.default_upstream.clone(),
+        })
+    }
+
+    pub fn select(&self, host: &str) -> Option<&str> {
+        if let Some(upstream) = self.exact.get(host) {
+            return Some(upstream);
+        }
+        self.wildcard
+            .iter()
+            .chain(self.regex.iter())
+            .find(|(pattern, _)| pattern.matches(host))
+            .map(|(_, upstream)| upstream.as_str())
+            .or(self.default.as_deref())
+    }
+}
+
+// Скомпилированное правило маршрутизации сервера
+pub struct Route {
+    host: Option<HostPattern>,
+    path_prefix: Option<String>,
+    path_regex: Option<Regex>,
+    methods: Vec<String>,
+    pub upstream: String,
+}
+
+impl Route {
+    pub fn new(config: &RouteConfig) -> Result<Self, String> {
+        let path_regex = config
+            .path_regex
+            .as_deref()
+            .map(Regex::new)
+            .transpose()
+            .map_err(|e| format!("invalid path_regex: {}", e))?;
+
+        Ok(Self {
+            host: config.host.as_deref().map(HostPattern::parse).transpose()?,
+            path_prefix: config.path_prefix.clone(),
+            path_regex,
+            methods: config.methods.iter().map(|m| m.to_uppercase()).collect(),
+            upstream: config.upstream.clone(),
+        })
+    }
+
+    // host - уже без порта и в нижнем регистре, path - после normalize_path
+    pub fn matches(&self, host: &str, path: &str, req: &RequestHeader) -> bool {
+        if self.host.as_ref().is_some_and(|pattern| !pattern.matches(host)) {
+            return false;
+        }
+        if !self.methods.is_empty() && !self.methods.iter().any(|m| m == req.method.as_str()) {
+            return false;
+        }
+
+        if self.path_prefix.as_deref().is_some_and(|prefix| !path.starts_with(prefix)) {
+            return false;
+        }
+        self.path_regex.as_ref().is_none_or(|re| re.is_match(path))
+    }
+}
+
+// Путь в том виде, в каком его обработает бэкенд: декодированы только unreserved-символы
+// (RFC 3986), убраны сегменты "." и "..", схлопнуты "//". Закодированные "/" и "\"
+// и некорректные %-последовательности отклоняются, чтобы путь нельзя было прочитать иначе
+pub fn normalize_path(path: &str) -> Result<String, String> {
+    let bytes = path.as_bytes();
+    let mut decoded: Vec<u8> = Vec::with_capacity(path.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] != b'%' {
+            decoded.push(bytes[i]);
+            i += 1;
+            continue;
+        }
+        let byte = path
+            .get(i + 1..i + 3)
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+            .ok_or_else(|| format!("invalid percent-encoding in path '{}'", path))?;
+        match byte {
+            b'/' | b'\\' => return Err(format!("encoded path separator in path '{}'", path)),
+            b if b.is_ascii_alphanumeric() || b"-._~".contains(&b) => decoded.push(b),
+            b => decoded.extend_from_slice(format!("%{:02X}", b).as_bytes()),
+        }
+        i += 3;
+    }
+    let decoded = String::from_utf8(decoded).map_err(|_| format!("invalid UTF-8 in path '{}'", path))?;
+
+    let mut segments: Vec<&str> = Vec::new();
+    let mut last = "";
+    for segment in decoded.split('/') {
+        match segment {
+            "" | "." => {}
+            ".." => {
+                segments.pop();
+            }
+            segment => segments.push(segment),
+        }
+        last = segment;
+    }
+
+    let mut normalized = format!("/{}", segments.join("/"));
+    // Завершающий "/" сохраняется: "/api/" и "/api" - разные пути
+    if !segments.is_empty() && matches!(last, "" | "." | "..") {
+        normalized.push('/');
+    }
+    Ok(normalized)
+}
+
+// Host запроса без порта и в нижнем регистре; для HTTP/2 берётся из :authority
+pub fn request_host(req: &RequestHeader) -> String {
+    let host = req
+        .headers
+        .get("host")
+        .and_then(|h| h.to_str().ok())
+        .or_else(|| req.uri.host())
+        .unwrap_or("");
+    host_without_port(host).trim_end_matches('.').to_lowercase()
+}
+
+// "example.com:8080" -> "example.com", "[::1]:8080" -> "[::1]"
+pub fn host_without_port(host: &str) -> &str {
+    if host.starts_with('[') {
+        return host.find(']').map_or(host, |end| &host[..=end]);
+    }
+    host.split_once(':').map_or(host, |(name, _)| name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn route(path_prefix: &str) -> Route {
+        Route::new(&RouteConfig {
+            host: None,
+            path_prefix: Some(path_prefix.to_string()),
+            path_regex: None,
+            methods: Vec::new(),
+            upstream: "api".to_string(),
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn normalize_resolves_dot_segments() {
+        assert_eq!(normalize_path("/api/../admin").unwrap(), "/admin");
+        assert_eq!(normalize_path("/api/./v1/./users").unwrap(), "/api/v1/users");
+        assert_eq!(normalize_path("/../../etc/passwd").unwrap(), "/etc/passwd");
+        assert_eq!(normalize_path("/api/v1/..").unwrap(), "/api/");
+    }
+
+    #[test]
+    fn normalize_collapses_slashes() {
+        assert_eq!(normalize_path("//api//v1///users").unwrap(), "/api/v1/users");
+        assert_eq!(normalize_path("/api/").unwrap(), "/api/");
+        assert_eq!(normalize_path("/").unwrap(), "/");
+        assert_eq!(normalize_path("").unwrap(), "/");
+    }
+
+    #[test]
+    fn normalize_decodes_unreserved_only() {
+        assert_eq!(normalize_path("/%61pi/%7Euser").unwrap(), "/api/~user");
+        assert_eq!(normalize_path("/api/%2e%2e/admin").unwrap(), "/admin");
+        assert_eq!(normalize_path("/api/%2E%2E/admin").unwrap(), "/admin");
+        assert_eq!(normalize_path("/search/a%20b%3fc").unwrap(), "/search/a%20b%3Fc");
+        assert_eq!(normalize_path("/a%2541").unwrap(), "/a%2541");
+    }
+
+    #[test]
+    fn normalize_rejects_ambiguous_paths() {
+        assert!(normalize_path("/api%2F..%2Fadmin").is_err());
+        assert!(normalize_path("/api%5c..%5cadmin").is_err());
+        assert!(normalize_path("/api/%zz").is_err());
+        assert!(normalize_path("/api/%4").is_err());
+    }
+
+    #[test]
+    fn route_matches_normalized_path() {
+        let route = route("/api/");
+        let req = RequestHeader::build("GET", b"/", None).unwrap();
+
+        let path = normalize_path("/api/%2e%2e/admin").unwrap();
+        assert!(!route.matches("example.com", &path, &req));
+
+        let path = normalize_path("//api//v1/./users").unwrap();
+        assert!(route.matches("example.com", &path, &req));
+    }
+}
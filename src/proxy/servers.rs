@@ -1,100 +1,379 @@
+use std::collections::HashSet;
 use std::sync::Arc;
-use tokio::sync::RwLock;
 use std::time::Duration;
-use tokio::time::sleep;
-use tracing::info;
 
-use crate::config::config::Config;
-use crate::proxy::proxy::MyProxy;
+use arc_swap::ArcSwap;
+use bytes::Bytes;
+use parking_lot::Mutex;
+use pingora::http::{RequestHeader, ResponseHeader};
+use pingora::protocols::Digest;
+use pingora::proxy::{ProxyHttp, Session};
+use pingora::upstreams::peer::HttpPeer;
+use pingora::{Error, ErrorType, Result};
+use tokio::runtime::Handle;
+use tokio::signal::unix::{signal, SignalKind};
+use tracing::{error, info, warn};
 
-// Простой менеджер серверов
+use crate::config::config::{Config, RealIpSource, ServerConfig};
+use crate::proxy::ban::ban_list;
+use crate::proxy::proxy::{MyProxy, RequestContext};
+use crate::proxy::proxy_manager::ProxyManager;
+use crate::proxy::proxy_protocol;
+use crate::proxy::tls;
+use crate::web::metrics;
+
+// Менеджер серверов: держит текущее состояние прокси и подменяет его при перезагрузке конфига
 pub struct ServerManager {
-    pub config: Arc<RwLock<Config>>,
-    pub running: Arc<RwLock<bool>>,
+    current: Arc<ArcSwap<ProxyManager>>,
+    // Адреса, на которых уже запущен листенер Pingora
+    listeners: Mutex<HashSet<String>>,
+    // Перезагрузки выполняются по одной
+    reload_lock: Mutex<()>,
+    runtime: Handle,
 }
 
 impl ServerManager {
-    pub fn new(config: Config) -> Self {
-        Self {
-            config: Arc::new(RwLock::new(config)),
-            running: Arc::new(RwLock::new(true)),
-        }
-    }
-    
-    // Основной метод запуска серверов
-    pub async fn run(&self) -> Result<(), Box<dyn std::error::Error>> {
-        let mut server_tasks = vec![];
-        
-        // Пока флаг running = true, серверы работают
-        while *self.running.read().await {
-            let config = self.config.read().await.clone();
-            
-            // Запускаем каждый сервер в отдельной задаче
-            for (server_name, server_config) in &config.servers.clone() {
-                let listen_addr = server_config.listen_addr.clone()
-                    .or_else(|| Some(server_config.addr.clone()))
-                    .unwrap_or_else(|| format!("0.0.0.0:{}", 8080));
-                
-                let _proxy = MyProxy::new_for_server(config.clone(), server_name);
-                
-                let task_name = server_name.clone();
-                let task_addr = listen_addr.clone();
-                
-                // Запускаем сервер в отдельной задаче
-                let handle = tokio::spawn(async move {
-                    info!("Starting server '{}' on {}", task_name, task_addr);
-                    
-                    // Здесь должна быть логика запуска сервера Pingora
-                    // Например: server.run_forever().await;
-                    
-                    // Для примера - бесконечный цикл
-                    loop {
-                        tokio::time::sleep(Duration::from_secs(3600)).await;
-                    }
-                });
-                
-                server_tasks.push(handle);
+    pub fn new(config: Config, runtime: Handle) -> Result<Self, String> {
+        ban_list().configure(config.ban.clone());
+        let proxy_manager = ProxyManager::new(config)?;
+        proxy_manager.spawn_health_checks(&runtime, None);
+        proxy_manager.spawn_access_watchers(&runtime);
+
+        Ok(Self {
+            current: Arc::new(ArcSwap::from_pointee(proxy_manager)),
+            listeners: Mutex::new(HashSet::new()),
+            reload_lock: Mutex::new(()),
+            runtime,
+        })
+    }
+
+    // Текущее состояние; запросы в работе продолжают использовать свой снимок
+    pub fn current(&self) -> Arc<ProxyManager> {
+        self.current.load_full()
+    }
+
+    // Запускает листенеры для серверов, адреса которых ещё не слушаются
+    pub fn start_listeners(&self) {
+        let proxy_manager = self.current();
+        for (server_name, server_config) in proxy_manager.config.get_servers() {
+            self.start_listener(server_name, server_config, &proxy_manager.config);
+        }
+    }
+
+    fn start_listener(&self, server_name: &str, server_config: &ServerConfig, config: &Config) {
+        let addr = server_config.addr.clone();
+        if !self.listeners.lock().insert(addr.clone()) {
+            return;
+        }
+
+        let tls_settings = match tls::tls_settings(server_config, config) {
+            Ok(settings) => settings,
+            Err(e) => {
+                error!(address = %addr, error = %e, "Failed to configure TLS for server '{}'", server_name);
+                self.listeners.lock().remove(&addr);
+                return;
+            }
+        };
+
+        // С PROXY protocol Pingora слушает loopback, а публичный адрес занимает ретранслятор
+        let internal = if server_config.real_ip_source == RealIpSource::ProxyProtocol {
+            match proxy_protocol::internal_listener() {
+                Ok((listener, internal)) => {
+                    self.runtime.spawn(proxy_protocol::run_relay(addr.clone(), internal));
+                    Some((listener, internal))
+                }
+                Err(e) => {
+                    error!(address = %addr, error = %e, "Failed to bind internal listener for server '{}'", server_name);
+                    self.listeners.lock().remove(&addr);
+                    return;
+                }
+            }
+        } else {
+            None
+        };
+        let listen_addr = internal.as_ref().map_or_else(|| addr.clone(), |(_, internal)| internal.to_string());
+
+        let handle = ServerProxy {
+            addr: addr.clone(),
+            current: self.current.clone(),
+        };
+        let server_name = server_name.to_string();
+
+        std::thread::spawn(move || {
+            // Внутренний сокет уже открыт: Pingora получает его готовым, а не занимает порт заново
+            let server = match internal {
+                Some((listener, internal)) => proxy_protocol::internal_server(listener, internal),
+                None => pingora::server::Server::new(None).map_err(|e| e.to_string()),
+            };
+            let mut server = match server {
+                Ok(server) => server,
+                Err(e) => {
+                    error!(error = %e, "Failed to create server '{}'", server_name);
+                    return;
+                }
+            };
+            server.bootstrap();
+
+            let mut proxy_service = pingora::proxy::http_proxy_service(&server.configuration, handle);
+            match tls_settings {
+                Some(tls_settings) => {
+                    proxy_service.add_tls_with_settings(&listen_addr, None, tls_settings);
+                    info!(address = %addr, "TLS termination enabled for server '{}'", server_name);
+                }
+                None => proxy_service.add_tcp(&listen_addr),
+            }
+
+            server.add_service(proxy_service);
+
+            info!(address = %addr, "Proxy server '{}' started", server_name);
+            server.run_forever();
+        });
+    }
+
+    /// Перечитывает конфиг с диска и атомарно подменяет состояние всех серверов
+    pub fn reload(&self) -> Result<(), String> {
+        let result = self.apply_reload();
+        metrics::inc_config_reload(result.is_ok());
+        result
+    }
+
+    fn apply_reload(&self) -> Result<(), String> {
+        let _guard = self.reload_lock.lock();
+        let previous = self.current();
+
+        let config = Config::load(Some(&previous.config.path))?;
+        config.validate().map_err(|errors| errors.to_string())?;
+        let proxy_manager = previous.rebuild(config)?;
+
+        if proxy_manager.config.admin_port != previous.config.admin_port {
+            warn!("admin_port change requires restart");
+        }
+        // Логирование и audit log настраиваются один раз при запуске
+        if proxy_manager.config.tracing != previous.config.tracing {
+            warn!("[tracing] settings change requires restart");
+        }
+        if proxy_manager.config.audit_log != previous.config.audit_log {
+            warn!("[audit_log] settings change requires restart");
+        }
+        for (server_name, server_config) in proxy_manager.config.get_servers() {
+            let running = previous.config.get_servers().values().find(|s| s.addr == server_config.addr);
+            if running.is_some_and(|s| s.tls_cert != server_config.tls_cert
+                || s.tls_key != server_config.tls_key
+                || s.certificates != server_config.certificates)
+            {
+                warn!(address = %server_config.addr, "TLS settings change for server '{}' requires restart", server_name);
             }
-            
-            // Ждем завершения всех задач (никогда не завершатся в нормальном режиме)
-            for task in server_tasks {
-                let _ = task.await;
+            let proxy_protocol = |s: &ServerConfig| s.real_ip_source == RealIpSource::ProxyProtocol;
+            if running.is_some_and(|s| proxy_protocol(s) != proxy_protocol(server_config)) {
+                warn!(address = %server_config.addr, "PROXY protocol change for server '{}' requires restart", server_name);
             }
-            
-            // Если мы здесь, значит все задачи завершились
-            // Выходим из цикла
-            break;
         }
-        
+
+        proxy_manager.spawn_health_checks(&self.runtime, Some(&previous));
+        proxy_manager.spawn_access_watchers(&self.runtime);
+        // Текущие баны переживают перезагрузку, меняются только пороги
+        ban_list().configure(proxy_manager.config.ban.clone());
+        self.current.store(Arc::new(proxy_manager));
+        self.start_listeners();
+
+        let proxy_manager = self.current();
+        for addr in self.listeners.lock().iter() {
+            if proxy_manager.get_proxy_for_addr(addr).is_none() {
+                warn!(address = %addr, "Server removed from config, listener disabled");
+            }
+        }
+
+        info!(
+            servers = proxy_manager.get_server_list().len(),
+            upstreams = proxy_manager.balancers.len(),
+            "Configuration reloaded"
+        );
         Ok(())
     }
-    
-    // Перезагрузка с новым конфигом
-    pub async fn reload(&self, new_config: Config) -> Result<(), Box<dyn std::error::Error>> {
-        info!("Reloading server configuration...");
-        
-        // 1. Обновляем конфигурацию
-        let mut config_lock = self.config.write().await;
-        *config_lock = new_config;
-        drop(config_lock); // Освобождаем блокировку
-        
-        // 2. Останавливаем текущие серверы
-        self.stop().await;
-        
-        // 3. Ждем немного
-        sleep(Duration::from_millis(100)).await;
-        
-        // 4. Запускаем серверы заново
-        // (в реальности это сделает цикл в методе run)
-        
-        info!("Configuration reloaded successfully");
-        Ok(())
+
+    pub async fn watch_sighup(self: Arc<Self>) {
+        let mut stream = match signal(SignalKind::hangup()) {
+            Ok(stream) => stream,
+            Err(e) => {
+                error!(error = %e, "Failed to setup SIGHUP listener");
+                return;
+            }
+        };
+        info!("Watching SIGHUP for configuration reload");
+
+        while stream.recv().await.is_some() {
+            info!("Received SIGHUP, reloading configuration");
+            let manager = self.clone();
+            match tokio::task::spawn_blocking(move || manager.reload()).await {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => error!(error = %e, "Configuration reload failed, keeping previous configuration"),
+                Err(e) => error!(error = %e, "Configuration reload task failed"),
+            }
+        }
     }
-    
-    // Остановка серверов
-    pub async fn stop(&self) {
-        let mut running = self.running.write().await;
-        *running = false;
-        info!("Stopping all servers...");
+}
+
+// Контекст запроса со снимком прокси, взятым в начале запроса
+pub struct ServerCtx {
+    proxy: Option<Arc<MyProxy>>,
+    inner: Option<RequestContext>,
+}
+
+// Обработчик листенера: на каждый запрос берёт актуальный MyProxy для своего адреса
+pub struct ServerProxy {
+    addr: String,
+    current: Arc<ArcSwap<ProxyManager>>,
+}
+
+#[async_trait::async_trait]
+impl ProxyHttp for ServerProxy {
+    type CTX = ServerCtx;
+
+    fn new_ctx(&self) -> Self::CTX {
+        let proxy = self.current.load().get_proxy_for_addr(&self.addr);
+        let inner = proxy.as_ref().and_then(|proxy| proxy.new_ctx());
+        ServerCtx { proxy, inner }
+    }
+
+    async fn upstream_peer(&self, session: &mut Session, ctx: &mut Self::CTX) -> Result<Box<HttpPeer>> {
+        match &ctx.proxy {
+            Some(proxy) => proxy.upstream_peer(session, &mut ctx.inner).await,
+            None => Err(Error::explain(ErrorType::HTTPStatus(503), "Server removed from config")),
+        }
+    }
+
+    async fn request_filter(&self, session: &mut Session, ctx: &mut Self::CTX) -> Result<bool> {
+        match &ctx.proxy {
+            Some(proxy) => proxy.request_filter(session, &mut ctx.inner).await,
+            None => {
+                session.respond_error(503).await?;
+                Ok(true)
+            }
+        }
+    }
+
+    async fn request_body_filter(
+        &self,
+        session: &mut Session,
+        body: &mut Option<Bytes>,
+        end_of_stream: bool,
+        ctx: &mut Self::CTX,
+    ) -> Result<()> {
+        match &ctx.proxy {
+            Some(proxy) => proxy.request_body_filter(session, body, end_of_stream, &mut ctx.inner).await,
+            None => Ok(()),
+        }
+    }
+
+    async fn upstream_request_filter(
+        &self,
+        session: &mut Session,
+        upstream_request: &mut RequestHeader,
+        ctx: &mut Self::CTX,
+    ) -> Result<()> {
+        match &ctx.proxy {
+            Some(proxy) => proxy.upstream_request_filter(session, upstream_request, &mut ctx.inner).await,
+            None => Ok(()),
+        }
+    }
+
+    fn upstream_response_filter(
+        &self,
+        session: &mut Session,
+        upstream_response: &mut ResponseHeader,
+        ctx: &mut Self::CTX,
+    ) -> Result<()> {
+        match &ctx.proxy {
+            Some(proxy) => proxy.upstream_response_filter(session, upstream_response, &mut ctx.inner),
+            None => Ok(()),
+        }
     }
-}
\ No newline at end of file
+
+    async fn response_filter(
+        &self,
+        session: &mut Session,
+        upstream_response: &mut ResponseHeader,
+        ctx: &mut Self::CTX,
+    ) -> Result<()> {
+        match &ctx.proxy {
+            Some(proxy) => proxy.response_filter(session, upstream_response, &mut ctx.inner).await,
+            None => Ok(()),
+        }
+    }
+
+    fn response_body_filter(
+        &self,
+        session: &mut Session,
+        body: &mut Option<Bytes>,
+        end_of_stream: bool,
+        ctx: &mut Self::CTX,
+    ) -> Result<Option<Duration>> {
+        match &ctx.proxy {
+            Some(proxy) => proxy.response_body_filter(session, body, end_of_stream, &mut ctx.inner),
+            None => Ok(None),
+        }
+    }
+
+    async fn logging(&self, session: &mut Session, e: Option<&Error>, ctx: &mut Self::CTX) {
+        if let Some(proxy) = &ctx.proxy {
+            proxy.logging(session, e, &mut ctx.inner).await;
+        }
+    }
+
+    async fn connected_to_upstream(
+        &self,
+        session: &mut Session,
+        reused: bool,
+        peer: &HttpPeer,
+        #[cfg(unix)] fd: std::os::unix::io::RawFd,
+        #[cfg(windows)] sock: std::os::windows::io::RawSocket,
+        digest: Option<&Digest>,
+        ctx: &mut Self::CTX,
+    ) -> Result<()> {
+        match &ctx.proxy {
+            Some(proxy) => {
+                proxy
+                    .connected_to_upstream(
+                        session,
+                        reused,
+                        peer,
+                        #[cfg(unix)]
+                        fd,
+                        #[cfg(windows)]
+                        sock,
+                        digest,
+                        &mut ctx.inner,
+                    )
+                    .await
+            }
+            None => Ok(()),
+        }
+    }
+
+    fn error_while_proxy(
+        &self,
+        peer: &HttpPeer,
+        session: &mut Session,
+        e: Box<Error>,
+        ctx: &mut Self::CTX,
+        client_reused: bool,
+    ) -> Box<Error> {
+        match &ctx.proxy {
+            Some(proxy) => proxy.error_while_proxy(peer, session, e, &mut ctx.inner, client_reused),
+            None => e,
+        }
+    }
+
+    fn fail_to_connect(
+        &self,
+        session: &mut Session,
+        peer: &HttpPeer,
+        ctx: &mut Self::CTX,
+        e: Box<Error>,
+    ) -> Box<Error> {
+        match &ctx.proxy {
+            Some(proxy) => proxy.fail_to_connect(session, peer, &mut ctx.inner, e),
+            None => e,
+        }
+    }
+}
@@ -0,0 +1,212 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+
+use pingora::listeners::tls::TlsSettings;
+use pingora::listeners::TlsAccept;
+use pingora::protocols::tls::{CaType, TlsRef};
+use pingora::protocols::Digest;
+use pingora::tls::ext;
+use pingora::tls::pkey::{PKey, Private};
+use pingora::tls::ssl::NameType;
+use pingora::tls::x509::X509;
+use pingora::upstreams::peer::HttpPeer;
+use pingora::utils::tls::CertKey;
+use tracing::{debug, error, warn};
+
+use crate::config::config::{Config, ServerConfig, TlsVersion, UpstreamTlsConfig};
+
+// Сертификат с цепочкой и ключ, загруженные при старте
+struct CertifiedKey {
+    chain: Vec<X509>,
+    key: PKey<Private>,
+}
+
+impl CertifiedKey {
+    fn load(cert_path: &Path, key_path: &Path) -> Result<Self, String> {
+        let cert_pem = fs::read(cert_path)
+            .map_err(|e| format!("failed to read certificate {}: {}", cert_path.display(), e))?;
+        let key_pem = fs::read(key_path)
+            .map_err(|e| format!("failed to read key {}: {}", key_path.display(), e))?;
+
+        let chain = X509::stack_from_pem(&cert_pem)
+            .map_err(|e| format!("invalid certificate {}: {}", cert_path.display(), e))?;
+        if chain.is_empty() {
+            return Err(format!("no certificates found in {}", cert_path.display()));
+        }
+        let key = PKey::private_key_from_pem(&key_pem)
+            .map_err(|e| format!("invalid key {}: {}", key_path.display(), e))?;
+
+        Ok(Self { chain, key })
+    }
+
+    fn apply(&self, ssl: &mut TlsRef) -> Result<(), String> {
+        ext::ssl_use_certificate(ssl, &self.chain[0]).map_err(|e| e.to_string())?;
+        for intermediate in &self.chain[1..] {
+            ext::ssl_add_chain_cert(ssl, intermediate).map_err(|e| e.to_string())?;
+        }
+        ext::ssl_use_private_key(ssl, &self.key).map_err(|e| e.to_string())
+    }
+}
+
+// Выбор сертификата по SNI во время handshake
+struct SniCertificates {
+    default: Option<Arc<CertifiedKey>>,
+    exact: HashMap<String, Arc<CertifiedKey>>,
+    // "*.example.com" хранится как "example.com"
+    wildcard: HashMap<String, Arc<CertifiedKey>>,
+}
+
+impl SniCertificates {
+    fn find(&self, server_name: Option<&str>) -> Option<&Arc<CertifiedKey>> {
+        let Some(name) = server_name.map(|n| n.to_lowercase()) else {
+            return self.default.as_ref();
+        };
+
+        self.exact
+            .get(&name)
+            .or_else(|| {
+                name.split_once('.')
+                    .and_then(|(_, parent)| self.wildcard.get(parent))
+            })
+            .or(self.default.as_ref())
+    }
+}
+
+#[async_trait::async_trait]
+impl TlsAccept for SniCertificates {
+    async fn certificate_callback(&self, ssl: &mut TlsRef) {
+        let server_name = ssl.servername(NameType::HOST_NAME).map(|n| n.to_string());
+
+        match self.find(server_name.as_deref()) {
+            Some(cert) => {
+                if let Err(e) = cert.apply(ssl) {
+                    error!(sni = ?server_name, error = %e, "Failed to set TLS certificate");
+                }
+            }
+            None => debug!(sni = ?server_name, "No TLS certificate for SNI"),
+        }
+    }
+}
+
+/// Настройки TLS-листенера сервера. None - сервер слушает обычный HTTP.
+/// Пути к сертификатам и ключам - относительно файла конфигурации.
+pub fn tls_settings(server: &ServerConfig, config: &Config) -> Result<Option<TlsSettings>, String> {
+    let default = match (&server.tls_cert, &server.tls_key) {
+        (Some(cert), Some(key)) => Some((config.resolve_path(cert), config.resolve_path(key))),
+        (None, None) => None,
+        _ => return Err("both tls_cert and tls_key must be set".to_string()),
+    };
+
+    if server.certificates.is_empty() {
+        return match default {
+            Some((cert, key)) => TlsSettings::intermediate(&cert.to_string_lossy(), &key.to_string_lossy())
+                .map(Some)
+                .map_err(|e| e.to_string()),
+            None => Ok(None),
+        };
+    }
+
+    let mut sni = SniCertificates {
+        default: match default {
+            Some((cert, key)) => Some(Arc::new(CertifiedKey::load(&cert, &key)?)),
+            None => None,
+        },
+        exact: HashMap::new(),
+        wildcard: HashMap::new(),
+    };
+
+    if sni.default.is_none() {
+        warn!(server = %server.addr, "No default TLS certificate, clients without matching SNI will fail the handshake");
+    }
+
+    for entry in &server.certificates {
+        let cert = Arc::new(CertifiedKey::load(&config.resolve_path(&entry.cert), &config.resolve_path(&entry.key))?);
+        for name in &entry.server_names {
+            let name = name.to_lowercase();
+            match name.strip_prefix("*.") {
+                Some(parent) => sni.wildcard.insert(parent.to_string(), cert.clone()),
+                None => sni.exact.insert(name, cert.clone()),
+            };
+        }
+    }
+
+    TlsSettings::with_callbacks(Box::new(sni))
+        .map(Some)
+        .map_err(|e| e.to_string())
+}
+
+// Настройки TLS к бэкендам upstream'а; сертификаты читаются один раз при сборке конфига
+pub struct UpstreamTls {
+    sni: Option<String>,
+    ca: Option<Arc<CaType>>,
+    client_cert_key: Option<Arc<CertKey>>,
+    verify_cert: bool,
+    verify_hostname: bool,
+    min_version: Option<TlsVersion>,
+}
+
+impl UpstreamTls {
+    pub fn load(tls: &UpstreamTlsConfig, config: &Config) -> Result<Self, String> {
+        let ca = match &tls.ca_file {
+            Some(path) => {
+                let path = config.resolve_path(path);
+                let pem = fs::read(&path)
+                    .map_err(|e| format!("failed to read CA file {}: {}", path.display(), e))?;
+                let certs = X509::stack_from_pem(&pem)
+                    .map_err(|e| format!("invalid CA file {}: {}", path.display(), e))?;
+                if certs.is_empty() {
+                    return Err(format!("no certificates found in {}", path.display()));
+                }
+                Some(Arc::new(certs.into_boxed_slice()))
+            }
+            None => None,
+        };
+
+        let client_cert_key = match (&tls.client_cert, &tls.client_key) {
+            (Some(cert), Some(key)) => {
+                let cert = config.resolve_path(cert);
+                let key = config.resolve_path(key);
+                let loaded = CertifiedKey::load(&cert, &key)?;
+                Some(Arc::new(CertKey::new(loaded.chain, loaded.key)))
+            }
+            (None, None) => None,
+            _ => return Err("both client_cert and client_key must be set".to_string()),
+        };
+
+        Ok(Self {
+            sni: tls.sni.clone(),
+            ca,
+            client_cert_key,
+            verify_cert: tls.verify_cert,
+            verify_hostname: tls.verify_hostname,
+            min_version: tls.min_version,
+        })
+    }
+
+    pub fn apply(&self, peer: &mut HttpPeer) {
+        if let Some(sni) = &self.sni {
+            peer.sni = sni.clone();
+        }
+        peer.options.ca = self.ca.clone();
+        peer.client_cert_key = self.client_cert_key.clone();
+        peer.options.verify_cert = self.verify_cert;
+        peer.options.verify_hostname = self.verify_hostname;
+    }
+
+    // Pingora задаёт минимальную версию только для всего коннектора,
+    // поэтому версия проверяется уже после handshake
+    pub fn check_version(&self, digest: Option<&Digest>) -> Result<(), String> {
+        let Some(min_version) = self.min_version else {
+            return Ok(());
+        };
+        let Some(ssl) = digest.and_then(|d| d.ssl_digest.as_ref()) else {
+            return Err("no TLS session information for upstream connection".to_string());
+        };
+        match TlsVersion::from_openssl(ssl.version) {
+            Some(version) if version >= min_version => Ok(()),
+            _ => Err(format!("upstream negotiated {}, minimum is TLS {}", ssl.version, min_version.as_str())),
+        }
+    }
+}
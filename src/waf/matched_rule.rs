@@ -0,0 +1,75 @@
+use std::sync::LazyLock;
+
+use regex::Regex;
+use serde::Serialize;
+
+// [id "942100"] [msg "SQL Injection Attack Detected via libinjection"] ...
+static FIELD_RE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r#"\[(\w+) "((?:[^"\\]|\\.)*)"\]"#).unwrap());
+
+// ... against variable `ARGS:id' (Value: `1 OR 1=1' )
+static VARIABLE_RE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"against variable `([^']*)'").unwrap());
+
+/// Сработавшее правило, разобранное из сообщения ModSecurity
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct MatchedRule {
+    pub id: u32,
+    pub msg: Option<String>,
+    pub severity: Option<u8>,
+    pub tags: Vec<String>,
+    pub matched_var: Option<String>,
+    pub data: Option<String>,
+    pub file: Option<String>,
+    pub line: Option<u32>,
+}
+
+impl MatchedRule {
+    /// Разбирает строку лога ModSecurity. Возвращает None, если в ней нет id правила.
+    pub fn parse(log: &str) -> Option<Self> {
+        let mut rule = MatchedRule::default();
+        let mut has_id = false;
+
+        for caps in FIELD_RE.captures_iter(log) {
+            let value = caps[2].replace("\\\"", "\"");
+            match &caps[1] {
+                "id" => {
+                    rule.id = value.parse().ok()?;
+                    has_id = true;
+                }
+                "msg" if !value.is_empty() => rule.msg = Some(value),
+                "severity" => rule.severity = value.parse().ok(),
+                "tag" => rule.tags.push(value),
+                "data" if !value.is_empty() => rule.data = Some(value),
+                "file" => rule.file = Some(value),
+                "line" => rule.line = value.parse().ok(),
+                _ => {}
+            }
+        }
+
+        if !has_id {
+            return None;
+        }
+
+        rule.matched_var = VARIABLE_RE
+            .captures(log)
+            .map(|caps| caps[1].to_string());
+
+        Some(rule)
+    }
+
+    /// Название уровня severity по шкале syslog, как в CRS
+    pub fn severity_name(&self) -> Option<&'static str> {
+        let name = match self.severity? {
+            0 => "EMERGENCY",
+            1 => "ALERT",
+            2 => "CRITICAL",
+            3 => "ERROR",
+            4 => "WARNING",
+            5 => "NOTICE",
+            6 => "INFO",
+            _ => "DEBUG",
+        };
+        Some(name)
+    }
+}
@@ -1,4 +1,7 @@
 pub mod engine;
+pub mod matched_rule;
 pub mod reloader;
+pub mod transaction;
 pub use engine::Engine;
 pub use engine::WafCheckResult;
+pub use matched_rule::MatchedRule;
@@ -1,16 +1,18 @@
-use crate::waf::{Engine, WafCheckResult};
+use crate::waf::transaction::WafTransaction;
+use crate::waf::Engine;
+use crate::web::metrics;
+use arc_swap::ArcSwap;
 use std::{
     path::PathBuf,
-    sync::{Arc, RwLock},
+    sync::Arc,
 };
-use tokio::signal::unix::{signal, SignalKind};
-use pingora::http::HMap;
 
-use tracing::{debug, error, info, warn, instrument};
+use tracing::{debug, info, warn, instrument};
 
 #[derive(Clone)]
 pub struct SharedWaf {
-    pub inner: Arc<RwLock<Engine>>,
+    // Транзакции удерживают свой Arc<Engine>, поэтому замена движка не трогает запросы в работе
+    pub inner: Arc<ArcSwap<Engine>>,
     pub path: Arc<PathBuf>,
 }
 
@@ -21,7 +23,7 @@ impl SharedWaf {
         info!("WAF инициализирован с файлом правил: {:?}", path_buf);
 
         Self {
-            inner: Arc::new(RwLock::new(engine)),
+            inner: Arc::new(ArcSwap::from_pointee(engine)),
             path: Arc::new(path_buf),
         }
     }
@@ -31,47 +33,29 @@ impl SharedWaf {
     //     engine.check(&headers, &uri, "GET", None)
     // }
 
-    pub fn check_detailed(&self, headers: &HMap, uri: &str, _method: &str, _body: Option<&[u8]>) -> WafCheckResult {
-        let engine = self.inner.read().expect("WAF lock poisoned");
-        engine.check_detailed(&headers, &uri, &_method, _body)
-    }
-
-    // ... остальные методы остаются без изменений
-    pub async fn watch_sighup(&self) {
-        let mut stream = signal(SignalKind::hangup()).expect("failed to setup SIGHUP listener");
-        info!("Начато отслеживание SIGHUP для перезагрузки правил WAF");
-
-        while stream.recv().await.is_some() {
-            info!("Получен SIGHUP, перезагружаем правила WAF");
-            match Engine::load(&*self.path) {
-                Ok(new_engine) => {
-                    let rules_info = new_engine.get_rules_info();
-                    *self.inner.write().unwrap() = new_engine;
-                    info!("Правила WAF успешно перезагружены из {:?}", self.path);
-                    debug!(rules_detailed = %rules_info, "Детальная информация о правилах");
-                }
-                Err(err) => {
-                    error!(
-                        error = %err,
-                            "Не удалось получить блокировку для записи WAF engine"
-                        );
-                }
-            }
-        }
+    /// Новая транзакция на текущем наборе правил; id попадает в UNIQUE_ID ModSecurity
+    pub fn transaction(&self, id: &str) -> Result<WafTransaction, String> {
+        WafTransaction::new(self.inner.load_full(), id)
     }
 
     pub fn reload_now(&self) -> anyhow::Result<()> {
         info!("Принудительная перезагрузка правил WAF");
-        let new_engine = Engine::load(&*self.path)?;
+        let new_engine = match Engine::load(&*self.path) {
+            Ok(engine) => engine,
+            Err(e) => {
+                metrics::inc_rule_reload(&self.path.to_string_lossy(), false);
+                return Err(e);
+            }
+        };
+        metrics::inc_rule_reload(&self.path.to_string_lossy(), true);
         let rules_info = new_engine.get_rules_info();
-        *self.inner.write().unwrap() = new_engine;
+        self.inner.store(Arc::new(new_engine));
         info!("Правила WAF успешно перезагружены");
         debug!("{}", rules_info);
         Ok(())
     }
 
     pub fn get_rules_info(&self) -> String {
-        let engine = self.inner.read().expect("WAF lock poisoned");
-        engine.get_rules_info()
+        self.inner.load().get_rules_info()
     }
 }
@@ -0,0 +1,187 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use modsecurity::transaction::Transaction;
+use modsecurity::Intervention;
+use parking_lot::Mutex;
+use pingora::http::HMap;
+use tracing::warn;
+
+use crate::waf::{Engine, MatchedRule, WafCheckResult};
+
+/// Транзакция ModSecurity, живущая всё время обработки запроса.
+/// Фазы продвигаются по очереди: заголовки запроса, тело, ответ, логирование.
+pub struct WafTransaction {
+    // Поле tx объявлено первым: транзакция уничтожается раньше движка
+    tx: Transaction<'static>,
+    _engine: Arc<Engine>,
+    // Сообщения о сработавших правилах, ещё не разобранные
+    log: Arc<Mutex<Vec<String>>>,
+    // Идентификатор транзакции (X-Request-Id) для логов
+    id: String,
+}
+
+impl WafTransaction {
+    pub fn new(engine: Arc<Engine>, id: &str) -> Result<Self, String> {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let tx = engine.build_transaction(log.clone(), id)?;
+        // SAFETY: транзакция ссылается на ModSecurity и Rules внутри Engine,
+        // который удерживается через Arc в этой же структуре и удаляется после tx.
+        let tx = unsafe { std::mem::transmute::<Transaction<'_>, Transaction<'static>>(tx) };
+        Ok(Self { tx, _engine: engine, log, id: id.to_string() })
+    }
+
+    /// Фаза 0: адреса соединения, в правилах доступны как REMOTE_ADDR и SERVER_ADDR
+    pub fn process_connection(&mut self, client: &SocketAddr, server: Option<&SocketAddr>) -> Result<(), String> {
+        let (server_ip, server_port) = server
+            .map(|addr| (addr.ip().to_string(), addr.port()))
+            .unwrap_or_else(|| (String::new(), 0));
+        self.tx
+            .process_connection(&client.ip().to_string(), client.port() as i32, &server_ip, server_port as i32)
+            .map_err(|e| format!("Ошибка process_connection: {e}"))
+    }
+
+    /// Фаза 1: URI и заголовки запроса
+    pub fn process_request_headers(&mut self, headers: &HMap, uri: &str, method: &str) -> WafCheckResult {
+        if let Err(e) = self.tx.process_uri(uri, method, "1.1") {
+            return WafCheckResult::error(format!("Ошибка process_uri: {e}"));
+        }
+
+        for (name, value) in headers.iter() {
+            if let Ok(v) = value.to_str() {
+                if let Err(e) = self.tx.add_request_header(name.as_str(), v) {
+                    warn!(request_id = %self.id, header = %name, error = %e, "Failed to add request header to WAF transaction");
+                }
+            }
+        }
+
+        if let Err(e) = self.tx.process_request_headers() {
+            return WafCheckResult::error(format!("Ошибка process_request_headers: {e}"));
+        }
+
+        self.intervention_result()
+    }
+
+    /// Фаза 2, по частям: очередной фрагмент тела запроса.
+    /// Правила выполняются в process_request_body, здесь intervention возможен
+    /// только при превышении SecRequestBodyLimit; None - можно продолжать
+    pub fn append_request_body(&mut self, chunk: &[u8]) -> Option<WafCheckResult> {
+        if chunk.is_empty() {
+            return None;
+        }
+        if let Err(e) = self.tx.append_request_body(chunk) {
+            return Some(WafCheckResult::error(format!("Ошибка append_request_body: {e}")));
+        }
+
+        let intervention = self.tx.intervention()?;
+        Some(self.check_result(Some(intervention)))
+    }
+
+    /// Фаза 2: правила по уже переданному телу (вызывается и для пустого тела)
+    pub fn process_request_body(&mut self) -> WafCheckResult {
+        if let Err(e) = self.tx.process_request_body() {
+            return WafCheckResult::error(format!("Ошибка process_request_body: {e}"));
+        }
+
+        self.intervention_result()
+    }
+
+    /// Фаза 3: заголовки ответа
+    pub fn process_response_headers(&mut self, status: u16, headers: &HMap) -> WafCheckResult {
+        for (name, value) in headers.iter() {
+            if let Ok(v) = value.to_str() {
+                if let Err(e) = self.tx.add_response_header(name.as_str(), v) {
+                    warn!(request_id = %self.id, header = %name, error = %e, "Failed to add response header to WAF transaction");
+                }
+            }
+        }
+
+        if let Err(e) = self.tx.process_response_headers(status as i32, "HTTP 1.1") {
+            return WafCheckResult::error(format!("Ошибка process_response_headers: {e}"));
+        }
+
+        self.intervention_result()
+    }
+
+    /// Фаза 4: тело ответа
+    pub fn process_response_body(&mut self, body: &[u8]) -> WafCheckResult {
+        if !body.is_empty() {
+            if let Err(e) = self.tx.append_response_body(body) {
+                return WafCheckResult::error(format!("Ошибка append_response_body: {e}"));
+            }
+        }
+
+        if let Err(e) = self.tx.process_response_body() {
+            return WafCheckResult::error(format!("Ошибка process_response_body: {e}"));
+        }
+
+        self.intervention_result()
+    }
+
+    /// Фаза 5: логирование
+    pub fn process_logging(&mut self) {
+        if let Err(e) = self.tx.process_logging() {
+            warn!(request_id = %self.id, error = %e, "WAF process_logging failed");
+        }
+    }
+
+    fn intervention_result(&mut self) -> WafCheckResult {
+        let intervention = self.tx.intervention();
+        self.check_result(intervention)
+    }
+
+    fn check_result(&mut self, intervention: Option<Intervention>) -> WafCheckResult {
+        // Правила, сработавшие с момента прошлой проверки
+        let mut rules: Vec<MatchedRule> = Vec::new();
+        for line in self.log.lock().drain(..) {
+            if let Some(rule) = MatchedRule::parse(&line) {
+                if !rules.iter().any(|r| r.id == rule.id) {
+                    rules.push(rule);
+                }
+            }
+        }
+
+        match intervention {
+            Some(intervention) => {
+                let status = intervention.status();
+                let matched_rule = intervention.log()
+                    .and_then(MatchedRule::parse)
+                    .or_else(|| rules.last().cloned());
+                let message = matched_rule.as_ref()
+                    .and_then(|rule| rule.msg.clone())
+                    .or_else(|| intervention.log().map(|s| s.to_string()))
+                    .unwrap_or_else(|| format!("Blocked with status {}", status));
+
+                if let Some(rule) = &matched_rule {
+                    if !rules.iter().any(|r| r.id == rule.id) {
+                        rules.push(rule.clone());
+                    }
+                }
+
+                WafCheckResult {
+                    allowed: false,
+                    rule_id: matched_rule.as_ref().map(|rule| rule.id).unwrap_or(0),
+                    matched_rule,
+                    rules,
+                    header_name: None,
+                    header_value: None,
+                    reason: format!("Blocked: {}", message),
+                    status: u16::try_from(status).unwrap_or(0),
+                    redirect_url: intervention.url().map(|url| url.to_string()),
+                }
+            }
+            // Нет intervention - разрешаем
+            None => WafCheckResult {
+                allowed: true,
+                matched_rule: None,
+                rules,
+                header_name: None,
+                header_value: None,
+                reason: "Allowed by WAF".to_string(),
+                rule_id: 0,
+                status: 0,
+                redirect_url: None,
+            },
+        }
+    }
+}
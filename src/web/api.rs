@@ -1,20 +1,24 @@
 use hyper::service::{make_service_fn, service_fn};
 use hyper::{Body, Request, Response, Server as HyperServer};
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
 use std::sync::Arc;
+use std::time::Duration;
 use tracing::{info, error};
-use crate::proxy::proxy_manager::ProxyManager;
-//use crate::config::config::Config;
+use crate::proxy::ban::ban_list;
+use crate::proxy::servers::ServerManager;
+use crate::web::metrics;
 
-pub async fn run_admin_server(port: u16, proxy_manager: Arc<ProxyManager>) {
+pub async fn run_admin_server(port: u16, server_manager: Arc<ServerManager>) {
     let addr = SocketAddr::from(([127, 0, 0, 1], port));
     
     let make_svc = make_service_fn(move |_conn| {
-        let proxy_manager = proxy_manager.clone();
+        let server_manager = server_manager.clone();
         async move {
             Ok::<_, hyper::Error>(service_fn(move |req: Request<Body>| {
-                let proxy_manager = proxy_manager.clone();
+                let server_manager = server_manager.clone();
                 async move {
+                    // Снимок текущего состояния на время запроса
+                    let proxy_manager = server_manager.current();
                     match req.uri().path() {
                         "/reload" => {
                             match proxy_manager.reload_all_rules() {
@@ -39,6 +43,15 @@ pub async fn run_admin_server(port: u16, proxy_manager: Arc<ProxyManager>) {
                                     .unwrap(),
                             )
                         }
+                        "/metrics" => {
+                            Ok::<_, hyper::Error>(
+                                Response::builder()
+                                    .status(200)
+                                    .header("Content-Type", "text/plain; version=0.0.4")
+                                    .body(Body::from(metrics::render()))
+                                    .unwrap(),
+                            )
+                        }
                         "/health" => {
                             Ok::<_, hyper::Error>(
                                 Response::builder()
@@ -47,6 +60,24 @@ pub async fn run_admin_server(port: u16, proxy_manager: Arc<ProxyManager>) {
                                     .unwrap(),
                             )
                         }
+                        "/upstreams" => {
+                            let health = proxy_manager.get_upstreams_health();
+                            Ok::<_, hyper::Error>(
+                                Response::builder()
+                                    .status(200)
+                                    .body(Body::from(health))
+                                    .unwrap(),
+                            )
+                        }
+                        "/breakers" => {
+                            let breakers = proxy_manager.get_circuit_breakers();
+                            Ok::<_, hyper::Error>(
+                                Response::builder()
+                                    .status(200)
+                                    .body(Body::from(breakers))
+                                    .unwrap(),
+                            )
+                        }
                         "/info" => {
                             let info = proxy_manager.get_waf_info();
                             Ok::<_, hyper::Error>(
@@ -56,6 +87,92 @@ pub async fn run_admin_server(port: u16, proxy_manager: Arc<ProxyManager>) {
                                     .unwrap(),
                             )
                         }
+                        "/server/reload" => {
+                            if req.method() != hyper::Method::POST {
+                                return Ok(Response::builder()
+                                    .status(405)
+                                    .body(Body::from("Method not allowed"))
+                                    .unwrap());
+                            }
+                            // Перезагрузка конфигурации из файла
+                            let manager = server_manager.clone();
+                            match tokio::task::spawn_blocking(move || manager.reload()).await {
+                                Ok(Ok(())) => Ok(Response::builder()
+                                    .status(200)
+                                    .body(Body::from("Configuration reloaded successfully"))
+                                    .unwrap()),
+                                Ok(Err(e)) => Ok(Response::builder()
+                                    .status(500)
+                                    .body(Body::from(format!("❌ Config reload failed: {e}")))
+                                    .unwrap()),
+                                Err(e) => Ok(Response::builder()
+                                    .status(500)
+                                    .body(Body::from(format!("❌ Config reload failed: {e}")))
+                                    .unwrap()),
+                            }
+                        }
+                        "/bans" => {
+                            Ok::<_, hyper::Error>(
+                                Response::builder()
+                                    .status(200)
+                                    .body(Body::from(ban_list().describe()))
+                                    .unwrap(),
+                            )
+                        }
+                        path if path.starts_with("/bans/") => {
+                            let ip = path.strip_prefix("/bans/").unwrap_or("");
+                            if ip.parse::<IpAddr>().is_err() {
+                                return Ok(Response::builder()
+                                    .status(400)
+                                    .body(Body::from(format!("❌ Invalid IP address '{}'", ip)))
+                                    .unwrap());
+                            }
+
+                            match *req.method() {
+                                // POST /bans/{ip}?secs=N - ручной бан, по умолчанию на ban_secs
+                                hyper::Method::POST => {
+                                    let secs = req
+                                        .uri()
+                                        .query()
+                                        .and_then(|q| q.split('&').find_map(|p| p.strip_prefix("secs=")))
+                                        .map(|v| v.parse::<u64>());
+                                    let duration = match secs {
+                                        Some(Ok(secs)) if secs > 0 => Duration::from_secs(secs),
+                                        Some(_) => {
+                                            return Ok(Response::builder()
+                                                .status(400)
+                                                .body(Body::from("❌ secs must be a positive integer"))
+                                                .unwrap());
+                                        }
+                                        None => ban_list().ban_duration(),
+                                    };
+                                    ban_list().ban(ip, duration, "manual".to_string());
+                                    info!(client_ip = %ip, ban_secs = duration.as_secs(), "Client banned via admin API");
+                                    Ok(Response::builder()
+                                        .status(200)
+                                        .body(Body::from(format!("{} banned for {}s", ip, duration.as_secs())))
+                                        .unwrap())
+                                }
+                                hyper::Method::DELETE => {
+                                    if ban_list().unban(ip) {
+                                        info!(client_ip = %ip, "Client unbanned via admin API");
+                                        Ok(Response::builder()
+                                            .status(200)
+                                            .body(Body::from(format!("{} unbanned", ip)))
+                                            .unwrap())
+                                    } else {
+                                        Ok(Response::builder()
+                                            .status(404)
+                                            .body(Body::from(format!("{} is not banned", ip)))
+                                            .unwrap())
+                                    }
+                                }
+                                _ => Ok(Response::builder()
+                                    .status(405)
+                                    .body(Body::from("Method not allowed"))
+                                    .unwrap()),
+                            }
+                        }
                         path if path.starts_with("/server/") => {
                             let server_name = path.strip_prefix("/server/").unwrap_or("");
                             if server_name.is_empty() {
@@ -78,31 +195,10 @@ pub async fn run_admin_server(port: u16, proxy_manager: Arc<ProxyManager>) {
                                     .unwrap())
                             }
                         }
-                        // "/server/reload" => {
-                        //     if req.method() == hyper::Method::POST {
-                        //         // Перезагрузка конфигурации из файла
-                        //         let new_config = Config::load();
-                        //         match proxy_manager.reload_config(new_config).await {
-                        //             Ok(_) => Ok(Response::builder()
-                        //                 .status(200)
-                        //                 .body(Body::from("Configuration reloaded successfully"))
-                        //                 .unwrap()),
-                        //             Err(e) => Ok(Response::builder()
-                        //                 .status(500)
-                        //                 .body(Body::from(format!("❌ Config reload failed: {e}")))
-                        //                 .unwrap()),
-                        //         }
-                        //     } else {
-                        //         Ok(Response::builder()
-                        //             .status(405)
-                        //             .body(Body::from("Method not allowed"))
-                        //             .unwrap())
-                        //     }
-                        // }
                         _ => {
                             Ok(Response::builder()
                                 .status(404)
-                                .body(Body::from("❌ Endpoint not found. Available: /reload, /stats, /health, /info, /server/{name}"))
+                                .body(Body::from("❌ Endpoint not found. Available: /reload, /stats, /health, /info, /upstreams, /breakers, /metrics, /server/reload, /server/{name}, /bans, /bans/{ip}"))
                                 .unwrap())
                         }
                     }
@@ -114,7 +210,7 @@ pub async fn run_admin_server(port: u16, proxy_manager: Arc<ProxyManager>) {
     let server = HyperServer::bind(&addr).serve(make_svc);
 
     info!(address = %addr, "Admin API started");
-    info!("Available endpoints: /reload, /stats, /health, /info, /server/");
+    info!("Available endpoints: /reload, /stats, /health, /info, /upstreams, /breakers, /metrics, /server/reload, /server/, /bans");
 
     if let Err(e) = server.await {
         error!(error = %e, "Admin server error");
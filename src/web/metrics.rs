@@ -0,0 +1,176 @@
+use std::sync::LazyLock;
+use std::time::Instant;
+
+use prometheus::{
+    register_histogram_vec, register_int_counter_vec, Encoder, HistogramVec, IntCounterVec, TextEncoder,
+};
+
+// Все запросы, прошедшие через прокси
+static REQUESTS_TOTAL: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "centaur_requests_total",
+        "Total number of proxied requests",
+        &["server", "upstream"]
+    )
+    .unwrap()
+});
+
+// Нарушения WAF по правилу и фазе (header, body, response_headers, response_body)
+static WAF_VIOLATIONS_TOTAL: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "centaur_waf_violations_total",
+        "WAF violations by rule id and phase",
+        &["upstream", "rule_id", "phase", "action"]
+    )
+    .unwrap()
+});
+
+static BODY_TOO_LARGE_TOTAL: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "centaur_request_body_too_large_total",
+        "Requests rejected with 413 Payload Too Large",
+        &["upstream"]
+    )
+    .unwrap()
+});
+
+// Запросы от забаненных клиентов, отклонённые с 403
+static BANNED_REQUESTS_TOTAL: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "centaur_banned_requests_total",
+        "Requests rejected because the client IP is banned",
+        &["server"]
+    )
+    .unwrap()
+});
+
+// Запросы, отклонённые списками доступа
+static ACCESS_DENIED_TOTAL: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "centaur_access_denied_total",
+        "Requests rejected by IP/CIDR deny lists",
+        &["server"]
+    )
+    .unwrap()
+});
+
+// Запросы, отклонённые из-за открытых circuit breaker'ов всех бэкендов
+static CIRCUIT_OPEN_TOTAL: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "centaur_circuit_open_total",
+        "Requests rejected because all backends of the upstream have open circuit breakers",
+        &["upstream"]
+    )
+    .unwrap()
+});
+
+static WAF_DURATION: LazyLock<HistogramVec> = LazyLock::new(|| {
+    register_histogram_vec!(
+        "centaur_waf_duration_seconds",
+        "Time spent evaluating WAF rules per phase",
+        &["upstream", "phase"],
+        vec![0.0001, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25]
+    )
+    .unwrap()
+});
+
+// Время от выбора бэкенда до получения заголовков ответа
+static UPSTREAM_DURATION: LazyLock<HistogramVec> = LazyLock::new(|| {
+    register_histogram_vec!(
+        "centaur_upstream_duration_seconds",
+        "Time until upstream response headers are received",
+        &["upstream"],
+        vec![0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0]
+    )
+    .unwrap()
+});
+
+static UPSTREAM_RESPONSES_TOTAL: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "centaur_upstream_responses_total",
+        "Upstream responses by status code",
+        &["upstream", "status"]
+    )
+    .unwrap()
+});
+
+static RULE_RELOADS_TOTAL: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "centaur_rule_reloads_total",
+        "WAF rule reloads by result",
+        &["rules", "result"]
+    )
+    .unwrap()
+});
+
+static CONFIG_RELOADS_TOTAL: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "centaur_config_reloads_total",
+        "Configuration reloads by result",
+        &["result"]
+    )
+    .unwrap()
+});
+
+pub fn inc_requests(server: &str, upstream: &str) {
+    REQUESTS_TOTAL.with_label_values(&[server, upstream]).inc();
+}
+
+pub fn inc_waf_violation(upstream: &str, rule_id: u32, phase: &str, blocked: bool) {
+    let action = if blocked { "blocked" } else { "detected" };
+    WAF_VIOLATIONS_TOTAL
+        .with_label_values(&[upstream, &rule_id.to_string(), phase, action])
+        .inc();
+}
+
+pub fn inc_body_too_large(upstream: &str) {
+    BODY_TOO_LARGE_TOTAL.with_label_values(&[upstream]).inc();
+}
+
+pub fn inc_banned_request(server: &str) {
+    BANNED_REQUESTS_TOTAL.with_label_values(&[server]).inc();
+}
+
+pub fn inc_access_denied(server: &str) {
+    ACCESS_DENIED_TOTAL.with_label_values(&[server]).inc();
+}
+
+pub fn inc_circuit_open(upstream: &str) {
+    CIRCUIT_OPEN_TOTAL.with_label_values(&[upstream]).inc();
+}
+
+pub fn observe_waf(upstream: &str, phase: &str, started: Instant) {
+    WAF_DURATION
+        .with_label_values(&[upstream, phase])
+        .observe(started.elapsed().as_secs_f64());
+}
+
+pub fn observe_upstream(upstream: &str, status: u16, started: Option<Instant>) {
+    if let Some(started) = started {
+        UPSTREAM_DURATION
+            .with_label_values(&[upstream])
+            .observe(started.elapsed().as_secs_f64());
+    }
+    UPSTREAM_RESPONSES_TOTAL
+        .with_label_values(&[upstream, &status.to_string()])
+        .inc();
+}
+
+pub fn inc_rule_reload(rules: &str, success: bool) {
+    let result = if success { "success" } else { "failure" };
+    RULE_RELOADS_TOTAL.with_label_values(&[rules, result]).inc();
+}
+
+pub fn inc_config_reload(success: bool) {
+    let result = if success { "success" } else { "failure" };
+    CONFIG_RELOADS_TOTAL.with_label_values(&[result]).inc();
+}
+
+/// Метрики в текстовом формате Prometheus
+pub fn render() -> String {
+    let mut buffer = Vec::new();
+    if let Err(e) = TextEncoder::new().encode(&prometheus::gather(), &mut buffer) {
+        return format!("# failed to encode metrics: {e}\n");
+    }
+    String::from_utf8_lossy(&buffer).to_string()
+}
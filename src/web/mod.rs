@@ -1,2 +1,3 @@
 pub mod api;
+pub mod metrics;
 pub mod ui;
\ No newline at end of file